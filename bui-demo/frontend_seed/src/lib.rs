@@ -117,13 +117,13 @@ async fn send_message(msg: Callback) -> Msg {
         .set("Content-Type", "application/json;charset=UTF-8")
         .unwrap();
 
-    let mut opts = RequestInit::new();
-    opts.method("POST");
-    opts.cache(web_sys::RequestCache::NoStore);
-    opts.headers(&headers.into());
+    let opts = RequestInit::new();
+    opts.set_method("POST");
+    opts.set_cache(web_sys::RequestCache::NoStore);
+    opts.set_headers(&headers.into());
 
     let buf = serde_json::to_string(&msg).unwrap();
-    opts.body(Some(&JsValue::from_str(&buf)));
+    opts.set_body(&JsValue::from_str(&buf));
 
     let url = "callback";
     let request = Request::new_with_str_and_init(url, &opts).unwrap();
@@ -198,18 +198,33 @@ async fn post_callback(msg: Callback) -> Result<(), FetchError> {
     use web_sys::{Headers, Request, RequestInit, Response};
 
     let headers = Headers::new().unwrap();
+    #[cfg(not(feature = "msgpack"))]
     headers
         .set("Content-Type", "application/json;charset=UTF-8")
         .unwrap();
+    #[cfg(feature = "msgpack")]
+    let encoding = bui_backend_types::Encoding::MessagePack;
+    #[cfg(feature = "msgpack")]
+    headers
+        .set("Content-Type", encoding.content_type())
+        .unwrap();
 
-    let mut opts = RequestInit::new();
-    opts.method("POST");
-    opts.cache(web_sys::RequestCache::NoStore);
+    let opts = RequestInit::new();
+    opts.set_method("POST");
+    opts.set_cache(web_sys::RequestCache::NoStore);
     // opts.mode(web_sys::RequestMode::Cors);
-    opts.headers(&headers.into());
+    opts.set_headers(&headers.into());
     // set SameOrigin
-    let buf = serde_json::to_string(&msg).unwrap();
-    opts.body(Some(&JsValue::from_str(&buf)));
+    #[cfg(not(feature = "msgpack"))]
+    {
+        let buf = serde_json::to_string(&msg).unwrap();
+        opts.set_body(&JsValue::from_str(&buf));
+    }
+    #[cfg(feature = "msgpack")]
+    {
+        let buf = encoding.encode(&msg).unwrap();
+        opts.set_body(&js_sys::Uint8Array::from(buf.as_slice()).into());
+    }
 
     let url = "callback";
     let request = Request::new_with_str_and_init(url, &opts)?;
@@ -65,17 +65,13 @@ use clap::Parser;
 use parking_lot::RwLock;
 
 use async_change_tracker::ChangeTracker;
-use bui_backend::{
-    highlevel::{create_bui_app_inner, BuiAppInner},
-    AccessControl, CallbackHandler,
-};
+use bui_backend::{highlevel::BuiAppInner, AccessControl, BuiApp, CallbackHandler};
 use bui_backend_types::CallbackDataAndSession;
 
 use bui_demo_data::{Callback, Shared};
 
 #[derive(Debug)]
 struct Error {
-    #[allow(dead_code)]
     kind: ErrorKind,
 }
 
@@ -85,6 +81,17 @@ enum ErrorKind {
     Raw(String),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            ErrorKind::BuiBackend(e) => e.fmt(f),
+            ErrorKind::Raw(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl StdError for Error {}
+
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Self {
         Self { kind }
@@ -185,33 +192,19 @@ impl MyApp {
             name: "".into(),
         })));
 
-        let callback_handler = Box::new(MyCallbackHandler {
+        let callback_handler = MyCallbackHandler {
             shared_store: shared_store.clone(),
-        });
-
-        let chan_size = 10;
-        let (rx_conn, bui_server) = bui_backend::lowlevel::launcher(
-            config,
-            &auth,
-            chan_size,
-            "/events",
-            None,
-            callback_handler,
-        );
-
-        let handle = tokio::runtime::Handle::current();
-
-        // Create `inner`, which takes care of the browser communication details for us.
-        let (_, inner) = create_bui_app_inner(
-            handle,
-            None,
-            &auth,
-            shared_store,
-            Some("bui_backend".to_string()),
-            rx_conn,
-            bui_server,
-        )
-        .await?;
+        };
+
+        // Start serving. `inner` takes care of the browser communication
+        // details for us.
+        let app = BuiApp::builder(config, shared_store)
+            .auth(auth)
+            .event_name("bui_backend")
+            .callback_handler(callback_handler)
+            .build()
+            .await?;
+        let inner = app.inner;
 
         // Return our app.
         Ok(MyApp { inner })
@@ -29,10 +29,7 @@ where
     // Check that at least one of the needed files is there.
     let required: std::path::PathBuf = files_dir.as_ref().join("index.html");
     if !entries.contains(&required) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("no {:?} file", required),
-        ));
+        return Err(std::io::Error::other(format!("no {:?} file", required)));
     }
 
     let codegen_fname_str = format!("{}", codegen_fname.as_ref().display());
@@ -84,19 +81,17 @@ where
 
     writeln!(f, "use bui_backend::lowlevel::Config;")?;
     writeln!(f, "fn get_default_config() -> Config {{")?;
-    writeln!(f, "    Config {{")?;
+    writeln!(f, "    Config::new(")?;
     writeln!(
         f,
-        "        serve_filepath: std::path::Path::new(r#\"{}\"#),",
+        "        std::path::Path::new(r#\"{}\"#),",
         files_dir.as_ref().display()
     )?;
     #[cfg(feature = "bundle_files")]
     {
-        writeln!(f, "        bundled_files: &PUBLIC,")?;
+        writeln!(f, "        &PUBLIC,")?;
     }
-    writeln!(f, "        channel_size: 10,")?;
-    writeln!(f, "        cookie_name: \"client\".into(),")?;
-    writeln!(f, "    }}")?;
+    writeln!(f, "    )")?;
     writeln!(f, "}}")?;
 
     Ok(())
@@ -1,7 +1,14 @@
 //! Types to control access to HTTP API
 
-use bui_backend_types::AccessToken;
-use std::net::SocketAddr;
+use bui_backend_types::{AccessToken, Role};
+use parking_lot::RwLock;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::auth::{Authenticator, TokenAuthenticator};
 
 #[derive(Clone, Debug)]
 struct JwtSecret(Vec<u8>);
@@ -11,6 +18,7 @@ struct JwtSecret(Vec<u8>);
 pub struct AccessInfo {
     addr: SocketAddr,
     access_token: AccessToken,
+    authenticator: Arc<dyn Authenticator>,
     jwt_secret: JwtSecret,
 }
 
@@ -20,18 +28,34 @@ impl AccessInfo {
         access_token: AccessToken,
         jwt_secret: Vec<u8>,
     ) -> Result<Self, crate::Error> {
-        if let AccessToken::PreSharedToken(ref _token) = access_token {
+        if let AccessToken::PreSharedToken(ref token) = access_token {
             let jwt_secret = JwtSecret(jwt_secret);
+            let authenticator =
+                Arc::new(TokenAuthenticator::new().with_token(token.clone(), Role::Controller));
             let access_token = access_token.clone();
             Ok(Self {
                 addr,
                 access_token,
+                authenticator,
                 jwt_secret,
             })
         } else {
             Err(crate::Error::NonLocalhostRequiresPreSharedToken)
         }
     }
+
+    pub(crate) fn with_authenticator(
+        addr: SocketAddr,
+        authenticator: Arc<dyn Authenticator>,
+        jwt_secret: Vec<u8>,
+    ) -> Self {
+        Self {
+            addr,
+            access_token: AccessToken::NoToken,
+            authenticator,
+            jwt_secret: JwtSecret(jwt_secret),
+        }
+    }
 }
 
 /// Access control method for the HTTP API
@@ -45,7 +69,7 @@ pub enum AccessControl {
 
 impl AccessControl {
     /// The address to bind the server to (e.g. `0.0.0.0`)
-    pub(crate) fn bind_addr(&self) -> &SocketAddr {
+    pub fn bind_addr(&self) -> &SocketAddr {
         match self {
             AccessControl::Insecure(ref addr) => addr,
             AccessControl::WithToken(ref info) => &info.addr,
@@ -59,6 +83,15 @@ impl AccessControl {
         }
     }
 
+    /// The authenticator checking credentials, or `None` if access is not
+    /// restricted.
+    pub(crate) fn authenticator(&self) -> Option<Arc<dyn Authenticator>> {
+        match self {
+            AccessControl::Insecure(_) => None,
+            AccessControl::WithToken(ref info) => Some(info.authenticator.clone()),
+        }
+    }
+
     pub(crate) fn jwt_secret(&self) -> &[u8] {
         match self {
             AccessControl::Insecure(ref _addr) => b"insecure",
@@ -66,3 +99,166 @@ impl AccessControl {
         }
     }
 }
+
+/// A JWT secret signing session cookies.
+struct JwtKey {
+    encoding_key: jsonwebtoken::EncodingKey,
+    decoding_key: jsonwebtoken::DecodingKey,
+    /// Once replaced, cookies signed with this key are accepted until then.
+    valid_until: Option<Instant>,
+}
+
+impl JwtKey {
+    fn new(secret: &[u8]) -> Self {
+        Self {
+            encoding_key: jsonwebtoken::EncodingKey::from_secret(secret),
+            decoding_key: jsonwebtoken::DecodingKey::from_secret(secret),
+            valid_until: None,
+        }
+    }
+
+    fn is_valid(&self, now: Instant) -> bool {
+        self.valid_until.map(|t| now < t).unwrap_or(true)
+    }
+}
+
+struct AccessState {
+    token: AccessToken,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    /// The current key, followed by replaced keys still accepted.
+    keys: Vec<JwtKey>,
+}
+
+/// The access control in effect for a `BuiService`, which may be changed
+/// while serving.
+///
+/// Clones share the state, so that changes apply to all clones of the
+/// service.
+#[derive(Clone)]
+pub(crate) struct SharedAccess(Arc<RwLock<AccessState>>);
+
+impl SharedAccess {
+    pub(crate) fn new(auth: &AccessControl) -> Self {
+        Self(Arc::new(RwLock::new(AccessState {
+            token: auth.token(),
+            authenticator: auth.authenticator(),
+            keys: vec![JwtKey::new(auth.jwt_secret())],
+        })))
+    }
+
+    pub(crate) fn token(&self) -> AccessToken {
+        self.0.read().token.clone()
+    }
+
+    pub(crate) fn authenticator(&self) -> Option<Arc<dyn Authenticator>> {
+        self.0.read().authenticator.clone()
+    }
+
+    /// The key to sign new session cookies with.
+    pub(crate) fn encoding_key(&self) -> jsonwebtoken::EncodingKey {
+        self.0.read().keys[0].encoding_key.clone()
+    }
+
+    /// The keys to check session cookies with, the current key first.
+    pub(crate) fn decoding_keys(&self) -> Vec<jsonwebtoken::DecodingKey> {
+        let now = Instant::now();
+        self.0
+            .read()
+            .keys
+            .iter()
+            .filter(|key| key.is_valid(now))
+            .map(|key| key.decoding_key.clone())
+            .collect()
+    }
+
+    /// Replace the pre-shared token. Returns `false`, changing nothing, if
+    /// access is not controlled with a pre-shared token.
+    pub(crate) fn set_token(&self, token: &str) -> bool {
+        let mut state = self.0.write();
+        if let AccessToken::NoToken = state.token {
+            return false;
+        }
+        state.token = AccessToken::PreSharedToken(token.to_string());
+        state.authenticator = Some(Arc::new(
+            TokenAuthenticator::new().with_token(token, Role::Controller),
+        ));
+        true
+    }
+
+    /// Sign new session cookies with `secret`, accepting cookies signed with
+    /// the previous keys for `grace`.
+    pub(crate) fn rotate_jwt_secret(&self, secret: &[u8], grace: Duration) {
+        let now = Instant::now();
+        let until = now + grace;
+        let mut state = self.0.write();
+        state.keys.retain(|key| key.is_valid(now));
+        for key in state.keys.iter_mut() {
+            key.valid_until = Some(key.valid_until.map_or(until, |t| t.min(until)));
+        }
+        if grace.is_zero() {
+            state.keys.clear();
+        }
+        state.keys.insert(0, JwtKey::new(secret));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token() -> AccessToken {
+        AccessToken::PreSharedToken("token".into())
+    }
+
+    fn sign(access: &SharedAccess) -> String {
+        let header = jsonwebtoken::Header::default();
+        jsonwebtoken::encode(&header, &serde_json::json!({}), &access.encoding_key()).unwrap()
+    }
+
+    fn accepts(access: &SharedAccess, jwt: &str) -> bool {
+        let mut validation = jsonwebtoken::Validation::default();
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        access
+            .decoding_keys()
+            .iter()
+            .any(|key| jsonwebtoken::decode::<serde_json::Value>(jwt, key, &validation).is_ok())
+    }
+
+    #[test]
+    fn rotate_jwt_secret() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let info = AccessInfo::new(addr, token(), b"first".to_vec()).unwrap();
+        let access = SharedAccess::new(&AccessControl::WithToken(info));
+        let first = sign(&access);
+
+        access.rotate_jwt_secret(b"second", Duration::from_secs(3600));
+        let second = sign(&access);
+        assert_ne!(first, second);
+        assert!(accepts(&access, &first));
+        assert!(accepts(&access, &second));
+
+        // A later rotation does not extend the grace period of older keys.
+        access.rotate_jwt_secret(b"third", Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!accepts(&access, &first));
+        assert!(!accepts(&access, &second));
+        assert!(accepts(&access, &sign(&access)));
+
+        access.rotate_jwt_secret(b"fourth", Duration::from_secs(0));
+        assert_eq!(access.decoding_keys().len(), 1);
+    }
+
+    #[test]
+    fn set_token() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let access = SharedAccess::new(&AccessControl::Insecure(addr));
+        assert!(!access.set_token("token"));
+        assert_eq!(access.token(), AccessToken::NoToken);
+
+        let info = AccessInfo::new(addr, token(), b"secret".to_vec()).unwrap();
+        let access = SharedAccess::new(&AccessControl::WithToken(info));
+        assert!(access.set_token("token2"));
+        assert_eq!(access.token(), AccessToken::PreSharedToken("token2".into()));
+    }
+}
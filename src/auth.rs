@@ -0,0 +1,765 @@
+//! Authentication of browsers starting a session
+//!
+//! An [Authenticator] checks the credentials presented by a browser without a
+//! valid session cookie. Credentials are taken from the `token` query
+//! parameter, from an HTTP Basic `Authorization` header, or from a form with
+//! `username` and `password` fields posted to `/login`. Once authenticated,
+//! the browser receives a session cookie carrying the [Identity] and [Role],
+//! which are passed to callbacks in [bui_backend_types::CallbackDataAndSession].
+//!
+//! Repeated failed logins from the same address are answered with increasing
+//! delays, during which credentials from that address are not checked.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+pub use bui_backend_types::{Identity, Role};
+
+use crate::Address;
+
+/// Credentials presented by a browser.
+#[derive(Debug)]
+pub enum Credentials<'a> {
+    /// A token given in the `token` query parameter.
+    Token(&'a str),
+    /// A user name and password given with HTTP Basic authentication or
+    /// posted to `/login`.
+    Password {
+        /// The user name.
+        username: &'a str,
+        /// The password.
+        password: &'a str,
+    },
+}
+
+/// The result of checking [Credentials].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthOutcome {
+    /// The credentials are valid.
+    Authenticated {
+        /// The identity of the session, if any.
+        identity: Option<Identity>,
+        /// The role of the session.
+        role: Role,
+    },
+    /// The credentials are not valid.
+    Rejected,
+}
+
+/// Checks the credentials of browsers starting a session.
+///
+/// Set with [crate::highlevel::generate_auth_with_authenticator].
+pub trait Authenticator: std::fmt::Debug + Send + Sync {
+    /// Check `credentials`.
+    ///
+    /// This is called on a thread for blocking tasks of the tokio runtime
+    /// (see [tokio::task::spawn_blocking]), so it may take a while, for
+    /// example to verify a password hash or to query a database.
+    fn authenticate(&self, credentials: &Credentials<'_>) -> AuthOutcome;
+
+    /// The realm of an HTTP Basic authentication challenge.
+    ///
+    /// If this is `Some`, requests without a valid session are answered with
+    /// status 401 and a `WWW-Authenticate` header, such that the browser asks
+    /// for a user name and password. Note that browsers keep sending these,
+    /// so logging out immediately starts a new session.
+    fn basic_realm(&self) -> Option<&str> {
+        None
+    }
+
+    /// Whether to show a login form.
+    ///
+    /// If this is `true`, requests for pages without a valid session are
+    /// answered with a form posting a user name and password to `/login`.
+    fn login_form(&self) -> bool {
+        false
+    }
+}
+
+/// Accepts pre-shared tokens, each giving its sessions a role.
+///
+/// For example, a token for operators with [Role::Controller] and a token
+/// for observers with [Role::Viewer]. Sessions have no identity.
+#[derive(Clone, Default)]
+pub struct TokenAuthenticator {
+    tokens: Vec<(String, Role)>,
+}
+
+impl std::fmt::Debug for TokenAuthenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let roles: Vec<_> = self.tokens.iter().map(|(_, role)| role).collect();
+        f.debug_struct("TokenAuthenticator")
+            .field("roles", &roles)
+            .finish()
+    }
+}
+
+impl TokenAuthenticator {
+    /// Create an authenticator accepting no tokens.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept `token`, giving sessions the role `role`.
+    pub fn with_token<S: Into<String>>(mut self, token: S, role: Role) -> Self {
+        self.tokens.push((token.into(), role));
+        self
+    }
+}
+
+impl Authenticator for TokenAuthenticator {
+    fn authenticate(&self, credentials: &Credentials<'_>) -> AuthOutcome {
+        if let Credentials::Token(token) = credentials {
+            for (valid, role) in self.tokens.iter() {
+                if constant_time_eq(token.as_bytes(), valid.as_bytes()) {
+                    return AuthOutcome::Authenticated {
+                        identity: None,
+                        role: *role,
+                    };
+                }
+            }
+        }
+        AuthOutcome::Rejected
+    }
+}
+
+type PasswordVerifier = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+type RoleMapper = Box<dyn Fn(&str) -> Role + Send + Sync>;
+
+/// Accepts user names and passwords checked by a verifier.
+///
+/// The identity of a session is the user name. Its role is
+/// [Role::Controller] unless set with [PasswordAuthenticator::with_roles].
+/// Tokens are rejected.
+pub struct PasswordAuthenticator {
+    verifier: PasswordVerifier,
+    roles: Option<RoleMapper>,
+    basic_realm: Option<String>,
+    login_form: bool,
+}
+
+impl std::fmt::Debug for PasswordAuthenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PasswordAuthenticator")
+            .field("basic_realm", &self.basic_realm)
+            .field("login_form", &self.login_form)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PasswordAuthenticator {
+    /// Accept the user names and passwords for which `verifier` returns
+    /// `true`.
+    pub fn new<F>(verifier: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            verifier: Box::new(verifier),
+            roles: None,
+            basic_realm: None,
+            login_form: false,
+        }
+    }
+
+    /// Accept the user names and passwords of an htpasswd file.
+    #[cfg(feature = "htpasswd")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "htpasswd")))]
+    pub fn from_htpasswd(htpasswd: Htpasswd) -> Self {
+        Self::new(move |username, password| htpasswd.verify(username, password))
+    }
+
+    /// Give the sessions of the user `username` the role returned by
+    /// `roles(username)`.
+    pub fn with_roles<F>(mut self, roles: F) -> Self
+    where
+        F: Fn(&str) -> Role + Send + Sync + 'static,
+    {
+        self.roles = Some(Box::new(roles));
+        self
+    }
+
+    /// Ask browsers for credentials using HTTP Basic authentication with the
+    /// realm `realm`. See [Authenticator::basic_realm].
+    pub fn with_basic_realm<S: Into<String>>(mut self, realm: S) -> Self {
+        self.basic_realm = Some(realm.into());
+        self
+    }
+
+    /// Show a login form to browsers without a session. See
+    /// [Authenticator::login_form].
+    pub fn with_login_form(mut self) -> Self {
+        self.login_form = true;
+        self
+    }
+}
+
+impl Authenticator for PasswordAuthenticator {
+    fn authenticate(&self, credentials: &Credentials<'_>) -> AuthOutcome {
+        match credentials {
+            Credentials::Password { username, password } if (self.verifier)(username, password) => {
+                let role = match self.roles {
+                    Some(ref roles) => roles(username),
+                    None => Role::Controller,
+                };
+                AuthOutcome::Authenticated {
+                    identity: Some(Identity::new(*username)),
+                    role,
+                }
+            }
+            _ => AuthOutcome::Rejected,
+        }
+    }
+
+    fn basic_realm(&self) -> Option<&str> {
+        self.basic_realm.as_deref()
+    }
+
+    fn login_form(&self) -> bool {
+        self.login_form
+    }
+}
+
+/// User names and hashed passwords, as in an Apache htpasswd file.
+///
+/// Supported hashes are bcrypt (`$2y$`, as created by `htpasswd -B`), Apache
+/// MD5 (`$apr1$`, `htpasswd -m`) and SHA-1 (`{SHA}`, `htpasswd -s`).
+#[cfg(feature = "htpasswd")]
+#[cfg_attr(docsrs, doc(cfg(feature = "htpasswd")))]
+#[derive(Clone)]
+pub struct Htpasswd {
+    entries: std::collections::HashMap<String, String>,
+    /// A hash checked for unknown users, so that the time taken does not
+    /// reveal which users exist.
+    dummy_hash: Option<String>,
+}
+
+#[cfg(feature = "htpasswd")]
+impl std::fmt::Debug for Htpasswd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Htpasswd")
+            .field("users", &self.entries.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(feature = "htpasswd")]
+impl Htpasswd {
+    /// Read an htpasswd file.
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, crate::Error> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    /// Parse the contents of an htpasswd file.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(contents: &str) -> Result<Self, crate::Error> {
+        let mut entries = std::collections::HashMap::new();
+        let mut dummy_hash = None;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, hash) = line.split_once(':').ok_or_else(|| {
+                crate::Error::Htpasswd(format!("line {}: expected `user:hash`", i + 1))
+            })?;
+            if !(hash.starts_with("$2") || hash.starts_with("$apr1$") || hash.starts_with("{SHA}"))
+            {
+                return Err(crate::Error::Htpasswd(format!(
+                    "line {}: unsupported hash for user `{}`",
+                    i + 1,
+                    username
+                )));
+            }
+            dummy_hash.get_or_insert_with(|| hash.to_string());
+            entries.insert(username.to_string(), hash.to_string());
+        }
+        Ok(Self {
+            entries,
+            dummy_hash,
+        })
+    }
+
+    /// Check the password of the user `username`.
+    ///
+    /// For unknown users, the password is checked against the hash of
+    /// another user, so that the time taken does not reveal which users
+    /// exist.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        match self.entries.get(username) {
+            Some(hash) => verify_hash(hash, password),
+            None => {
+                if let Some(ref hash) = self.dummy_hash {
+                    std::hint::black_box(verify_hash(hash, password));
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Check `password` against a hash from an htpasswd file.
+#[cfg(feature = "htpasswd")]
+fn verify_hash(hash: &str, password: &str) -> bool {
+    if let Some(expected) = hash.strip_prefix("{SHA}") {
+        use base64::Engine;
+        use sha1::Digest;
+        let digest = sha1::Sha1::digest(password.as_bytes());
+        let actual = base64::engine::general_purpose::STANDARD.encode(digest);
+        constant_time_eq(actual.as_bytes(), expected.as_bytes())
+    } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or("");
+        let actual = apr1_md5(password.as_bytes(), salt.as_bytes());
+        constant_time_eq(actual.as_bytes(), hash.as_bytes())
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// Hash `password` with the Apache variant of the MD5 based crypt.
+#[cfg(feature = "htpasswd")]
+fn apr1_md5(password: &[u8], salt: &[u8]) -> String {
+    use md5::{Digest, Md5};
+
+    const MAGIC: &[u8] = b"$apr1$";
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+    let salt = &salt[..std::cmp::min(salt.len(), 8)];
+
+    let mut ctx = Md5::new();
+    ctx.update(password);
+    ctx.update(MAGIC);
+    ctx.update(salt);
+
+    let alt = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+    for chunk in (0..password.len()).step_by(16) {
+        ctx.update(&alt[..std::cmp::min(16, password.len() - chunk)]);
+    }
+    let mut i = password.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            ctx.update([0u8]);
+        } else {
+            ctx.update(&password[..1]);
+        }
+        i >>= 1;
+    }
+    let mut digest = ctx.finalize();
+
+    for i in 0..1000 {
+        let mut ctx = Md5::new();
+        if i & 1 == 1 {
+            ctx.update(password);
+        } else {
+            ctx.update(digest);
+        }
+        if i % 3 != 0 {
+            ctx.update(salt);
+        }
+        if i % 7 != 0 {
+            ctx.update(password);
+        }
+        if i & 1 == 1 {
+            ctx.update(digest);
+        } else {
+            ctx.update(password);
+        }
+        digest = ctx.finalize();
+    }
+
+    let mut out = String::from_utf8_lossy(MAGIC).into_owned();
+    out.push_str(&String::from_utf8_lossy(salt));
+    out.push('$');
+    let mut to64 = |mut v: u32, n: usize| {
+        for _ in 0..n {
+            out.push(ITOA64[(v & 0x3f) as usize] as char);
+            v >>= 6;
+        }
+    };
+    let d = |i: usize| digest[i] as u32;
+    to64((d(0) << 16) | (d(6) << 8) | d(12), 4);
+    to64((d(1) << 16) | (d(7) << 8) | d(13), 4);
+    to64((d(2) << 16) | (d(8) << 8) | d(14), 4);
+    to64((d(3) << 16) | (d(9) << 8) | d(15), 4);
+    to64((d(4) << 16) | (d(10) << 8) | d(5), 4);
+    to64(d(11), 2);
+    out
+}
+
+/// Compare secrets in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The login form shown to browsers without a session.
+pub(crate) fn login_form_html(action: &str, error: Option<&str>) -> String {
+    let error = match error {
+        Some(error) => format!("<p class=\"error\">{}</p>\n", error),
+        None => String::new(),
+    };
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Log in</title></head>
+<body>
+{error}<form method="post" action="{action}">
+<label>User name <input name="username" autocomplete="username" required></label>
+<label>Password <input name="password" type="password" autocomplete="current-password" required></label>
+<button type="submit">Log in</button>
+</form>
+</body>
+</html>
+"#,
+        error = error,
+        action = action,
+    )
+}
+
+/// Failed logins from an address before it has to wait between attempts.
+const FREE_LOGIN_ATTEMPTS: u32 = 5;
+
+/// The longest wait between login attempts from one address.
+const MAX_LOGIN_DELAY: Duration = Duration::from_secs(300);
+
+/// How long failed logins from an address are remembered.
+const FORGET_LOGIN_FAILURES: Duration = Duration::from_secs(3600);
+
+/// The number of addresses above which forgotten ones are removed.
+const MAX_TRACKED_ADDRESSES: usize = 1024;
+
+/// Slows down guessing credentials from one address.
+///
+/// After [FREE_LOGIN_ATTEMPTS] failed logins, credentials from the address
+/// are not checked until a delay has passed, which starts at one second and
+/// doubles with every further failure up to [MAX_LOGIN_DELAY]. A successful
+/// login resets the delay.
+///
+/// IPv6 addresses are grouped by their /64 prefix. Clients of a reverse proxy
+/// share the address of the proxy unless it is mounted in a server which adds
+/// the client [Address] extension to requests.
+#[derive(Debug, Default)]
+pub(crate) struct LoginThrottle {
+    failures: HashMap<IpAddr, LoginFailures>,
+}
+
+#[derive(Debug)]
+struct LoginFailures {
+    count: u32,
+    last: Instant,
+}
+
+impl LoginThrottle {
+    /// How long `remote_addr` has to wait before its credentials are checked.
+    pub(crate) fn retry_after(
+        &self,
+        remote_addr: Option<&Address>,
+        now: Instant,
+    ) -> Option<Duration> {
+        let failures = self.failures.get(&throttle_key(remote_addr)?)?;
+        let since = now.saturating_duration_since(failures.last);
+        if since >= FORGET_LOGIN_FAILURES {
+            return None;
+        }
+        let excess = failures.count.checked_sub(FREE_LOGIN_ATTEMPTS)?;
+        let delay = Duration::from_secs(1 << excess.min(16)).min(MAX_LOGIN_DELAY);
+        delay.checked_sub(since).filter(|wait| !wait.is_zero())
+    }
+
+    /// Remember the outcome of checking credentials from `remote_addr`.
+    pub(crate) fn record(
+        &mut self,
+        remote_addr: Option<&Address>,
+        outcome: &AuthOutcome,
+        now: Instant,
+    ) {
+        let key = match throttle_key(remote_addr) {
+            Some(key) => key,
+            None => return,
+        };
+        if let AuthOutcome::Authenticated { .. } = outcome {
+            self.failures.remove(&key);
+            return;
+        }
+        if self.failures.len() >= MAX_TRACKED_ADDRESSES && !self.failures.contains_key(&key) {
+            self.failures
+                .retain(|_, f| now.saturating_duration_since(f.last) < FORGET_LOGIN_FAILURES);
+        }
+        let failures = self.failures.entry(key).or_insert(LoginFailures {
+            count: 0,
+            last: now,
+        });
+        if now.saturating_duration_since(failures.last) >= FORGET_LOGIN_FAILURES {
+            failures.count = 0;
+        }
+        failures.count = failures.count.saturating_add(1);
+        failures.last = now;
+    }
+}
+
+/// The key under which failed logins from `remote_addr` are counted.
+///
+/// Clients of Unix domain sockets are not throttled.
+fn throttle_key(remote_addr: Option<&Address>) -> Option<IpAddr> {
+    match remote_addr? {
+        Address::Tcp(addr) => Some(match addr.ip().to_canonical() {
+            IpAddr::V6(ip) => {
+                let prefix = u128::from(ip) & !((1u128 << 64) - 1);
+                IpAddr::V6(prefix.into())
+            }
+            ip => ip,
+        }),
+        Address::Unix(_) => None,
+    }
+}
+
+/// Check `credentials` with `authenticator` on a thread for blocking tasks.
+pub(crate) async fn authenticate_blocking(
+    authenticator: Arc<dyn Authenticator>,
+    credentials: &Credentials<'_>,
+) -> AuthOutcome {
+    let check: Box<dyn FnOnce() -> AuthOutcome + Send> = match *credentials {
+        Credentials::Token(token) => {
+            let token = token.to_string();
+            Box::new(move || authenticator.authenticate(&Credentials::Token(&token)))
+        }
+        Credentials::Password { username, password } => {
+            let (username, password) = (username.to_string(), password.to_string());
+            Box::new(move || {
+                authenticator.authenticate(&Credentials::Password {
+                    username: &username,
+                    password: &password,
+                })
+            })
+        }
+    };
+    match tokio::task::spawn_blocking(check).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            error!("checking credentials failed: {}", e);
+            AuthOutcome::Rejected
+        }
+    }
+}
+
+/// An [Authenticator] which does not check credentials from an address
+/// while it has to wait after failed logins.
+#[derive(Debug)]
+pub(crate) struct Throttled {
+    pub(crate) inner: Arc<dyn Authenticator>,
+    pub(crate) throttle: Arc<Mutex<LoginThrottle>>,
+    pub(crate) remote_addr: Option<Address>,
+}
+
+impl Authenticator for Throttled {
+    fn authenticate(&self, credentials: &Credentials<'_>) -> AuthOutcome {
+        let now = Instant::now();
+        if self
+            .throttle
+            .lock()
+            .retry_after(self.remote_addr.as_ref(), now)
+            .is_some()
+        {
+            warn!(
+                "not checking credentials from {:?} after repeated failed logins",
+                self.remote_addr
+            );
+            return AuthOutcome::Rejected;
+        }
+        let outcome = self.inner.authenticate(credentials);
+        self.throttle
+            .lock()
+            .record(self.remote_addr.as_ref(), &outcome, Instant::now());
+        outcome
+    }
+
+    fn basic_realm(&self) -> Option<&str> {
+        self.inner.basic_realm()
+    }
+
+    fn login_form(&self) -> bool {
+        self.inner.login_form()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Created with `htpasswd -m`, `htpasswd -s` and `htpasswd -B`, all with the
+    // password `secret`.
+    #[cfg(feature = "htpasswd")]
+    const HTPASSWD: &str = "\
+# comment
+
+alice:$apr1$abcdefgh$h9FWgUz3n9YxylKLlR5SQ/
+bob:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=
+carol:$2y$05$abcdefghijklmnopqrstuuOQiyCxlgf/oeuTqixKmWdcYUh4Hjl0a
+";
+
+    #[test]
+    #[cfg(feature = "htpasswd")]
+    fn htpasswd_known_hashes() {
+        let htpasswd = Htpasswd::parse(HTPASSWD).unwrap();
+        for user in ["alice", "bob", "carol"] {
+            assert!(htpasswd.verify(user, "secret"), "{}", user);
+            assert!(!htpasswd.verify(user, "secreT"), "{}", user);
+            assert!(!htpasswd.verify(user, ""), "{}", user);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "htpasswd")]
+    fn htpasswd_apr1_md5() {
+        assert_eq!(
+            apr1_md5(b"secret", b"abcdefgh"),
+            "$apr1$abcdefgh$h9FWgUz3n9YxylKLlR5SQ/"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "htpasswd")]
+    fn htpasswd_unknown_user() {
+        let htpasswd = Htpasswd::parse(HTPASSWD).unwrap();
+        // The password of another user does not match.
+        assert!(!htpasswd.verify("dave", "secret"));
+        assert!(!htpasswd.verify("Alice", "secret"));
+        assert!(!Htpasswd::parse("").unwrap().verify("alice", "secret"));
+    }
+
+    #[test]
+    #[cfg(feature = "htpasswd")]
+    fn htpasswd_malformed_lines() {
+        let err = Htpasswd::parse("alice:$apr1$abcdefgh$h9FWgUz3n9YxylKLlR5SQ/\nbob\n");
+        assert!(
+            matches!(err, Err(crate::Error::Htpasswd(ref msg)) if msg.starts_with("line 2:")),
+            "{:?}",
+            err
+        );
+        // Plain text and crypt(3) DES passwords are not supported.
+        assert!(Htpasswd::parse("bob:secret").is_err());
+        assert!(Htpasswd::parse("bob:abJnggxhB/yWI").is_err());
+    }
+
+    fn tcp(addr: &str) -> Address {
+        Address::Tcp(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn login_throttle_backs_off() {
+        let alice = tcp("192.0.2.1:1000");
+        let bob = tcp("192.0.2.2:1000");
+        let mut throttle = LoginThrottle::default();
+        let t0 = Instant::now();
+        for _ in 0..FREE_LOGIN_ATTEMPTS {
+            assert_eq!(throttle.retry_after(Some(&alice), t0), None);
+            throttle.record(Some(&alice), &AuthOutcome::Rejected, t0);
+        }
+        // Other ports of the same address wait, too.
+        let alice2 = tcp("192.0.2.1:1001");
+        assert_eq!(
+            throttle.retry_after(Some(&alice2), t0),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(throttle.retry_after(Some(&bob), t0), None);
+        let t1 = t0 + Duration::from_secs(1);
+        assert_eq!(throttle.retry_after(Some(&alice), t1), None);
+
+        // Each further failure doubles the delay, up to the maximum.
+        throttle.record(Some(&alice), &AuthOutcome::Rejected, t1);
+        assert_eq!(
+            throttle.retry_after(Some(&alice), t1),
+            Some(Duration::from_secs(2))
+        );
+        for _ in 0..20 {
+            throttle.record(Some(&alice), &AuthOutcome::Rejected, t1);
+        }
+        assert_eq!(
+            throttle.retry_after(Some(&alice), t1),
+            Some(MAX_LOGIN_DELAY)
+        );
+
+        // A successful login resets the delay.
+        let authenticated = AuthOutcome::Authenticated {
+            identity: None,
+            role: Role::Controller,
+        };
+        throttle.record(Some(&alice), &authenticated, t1);
+        assert_eq!(throttle.retry_after(Some(&alice), t1), None);
+    }
+
+    #[test]
+    fn login_throttle_keys() {
+        let mut throttle = LoginThrottle::default();
+        let t0 = Instant::now();
+        for _ in 0..FREE_LOGIN_ATTEMPTS {
+            throttle.record(Some(&tcp("[2001:db8::1]:1000")), &AuthOutcome::Rejected, t0);
+            throttle.record(
+                Some(&tcp("[::ffff:192.0.2.1]:1000")),
+                &AuthOutcome::Rejected,
+                t0,
+            );
+            throttle.record(Some(&Address::Unix(None)), &AuthOutcome::Rejected, t0);
+            throttle.record(None, &AuthOutcome::Rejected, t0);
+        }
+        // IPv6 addresses are grouped by their /64 prefix.
+        assert!(throttle
+            .retry_after(Some(&tcp("[2001:db8::2]:1000")), t0)
+            .is_some());
+        assert!(throttle
+            .retry_after(Some(&tcp("[2001:db8:0:1::1]:1000")), t0)
+            .is_none());
+        // IPv4-mapped addresses count as IPv4 addresses.
+        assert!(throttle
+            .retry_after(Some(&tcp("192.0.2.1:1000")), t0)
+            .is_some());
+        // Clients of Unix domain sockets and unknown clients are not throttled.
+        assert!(throttle
+            .retry_after(Some(&Address::Unix(None)), t0)
+            .is_none());
+        assert!(throttle.retry_after(None, t0).is_none());
+        assert_eq!(throttle.failures.len(), 2);
+    }
+
+    #[test]
+    fn login_throttle_forgets() {
+        let alice = tcp("192.0.2.1:1000");
+        let mut throttle = LoginThrottle::default();
+        let t0 = Instant::now();
+        for _ in 0..FREE_LOGIN_ATTEMPTS + 20 {
+            throttle.record(Some(&alice), &AuthOutcome::Rejected, t0);
+        }
+        let later = t0 + FORGET_LOGIN_FAILURES;
+        assert_eq!(throttle.retry_after(Some(&alice), later), None);
+        throttle.record(Some(&alice), &AuthOutcome::Rejected, later);
+        assert_eq!(throttle.retry_after(Some(&alice), later), None);
+
+        // Forgotten addresses are removed once many are tracked.
+        for i in 0..MAX_TRACKED_ADDRESSES as u32 {
+            let addr = Address::Tcp((std::net::Ipv4Addr::from(i), 1000).into());
+            throttle.record(Some(&addr), &AuthOutcome::Rejected, t0);
+        }
+        assert_eq!(throttle.failures.len(), MAX_TRACKED_ADDRESSES + 1);
+        let much_later = later + FORGET_LOGIN_FAILURES;
+        throttle.record(
+            Some(&tcp("192.0.2.2:1000")),
+            &AuthOutcome::Rejected,
+            much_later,
+        );
+        assert_eq!(throttle.failures.len(), 1);
+    }
+}
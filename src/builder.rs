@@ -0,0 +1,384 @@
+//! Set up and start a BUI application in one step
+//!
+//! [BuiApp::builder] replaces calling [crate::lowlevel::launcher] and then
+//! [crate::highlevel::create_bui_app_inner], which remain available for
+//! finer control.
+
+use std::{future::Future, sync::Arc};
+
+use async_change_tracker::ChangeTracker;
+use parking_lot::RwLock;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{
+    access_control::AccessControl,
+    highlevel::{BuiAppInner, ConnectionEvent, ViewContext, ViewFn},
+    lowlevel::{
+        CallbackHandlerWithResponse, Config, DynCallbackHandler, OverflowPolicy, RawReqHandler,
+        UpdateMode,
+    },
+    Error, Listener,
+};
+
+type ShutdownSignal = std::pin::Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A running BUI application.
+pub struct BuiApp<T, CB> {
+    /// Maintains the state and serves it to the browsers.
+    pub inner: BuiAppInner<T, CB>,
+    /// Receives an event whenever an event stream connects or disconnects.
+    pub connection_events: mpsc::Receiver<ConnectionEvent>,
+}
+
+impl<T, CB> BuiApp<T, CB>
+where
+    T: Clone + Serialize + 'static + Send + Sync,
+    CB: serde::de::DeserializeOwned + Clone + Send + 'static,
+{
+    /// Start building a BUI application serving `shared_arc` with `config`.
+    ///
+    /// `config` is usually created by the `get_default_config()` function
+    /// from `bui_backend_codegen`.
+    pub fn builder(
+        config: Config,
+        shared_arc: Arc<RwLock<ChangeTracker<T>>>,
+    ) -> BuiAppBuilder<T, CB> {
+        BuiAppBuilder {
+            config,
+            shared_arc,
+            listeners: Vec::new(),
+            auths: Vec::new(),
+            events_path: "/events".to_string(),
+            event_name: None,
+            view: None,
+            raw_req_handler: None,
+            callback_handler: None,
+            new_connection_channel_size: None,
+            connection_event_channel_size: None,
+            shutdown_signal: None,
+            handle: None,
+        }
+    }
+}
+
+/// Builds a [BuiApp]. Created with [BuiApp::builder].
+pub struct BuiAppBuilder<T, CB> {
+    config: Config,
+    shared_arc: Arc<RwLock<ChangeTracker<T>>>,
+    listeners: Vec<(Listener, AccessControl)>,
+    auths: Vec<AccessControl>,
+    events_path: String,
+    event_name: Option<String>,
+    view: Option<ViewFn<T>>,
+    raw_req_handler: Option<RawReqHandler>,
+    callback_handler: Option<Box<dyn DynCallbackHandler<Data = CB>>>,
+    new_connection_channel_size: Option<usize>,
+    connection_event_channel_size: Option<usize>,
+    shutdown_signal: Option<ShutdownSignal>,
+    handle: Option<tokio::runtime::Handle>,
+}
+
+impl<T, CB> BuiAppBuilder<T, CB>
+where
+    T: Clone + Serialize + 'static + Send + Sync,
+    CB: serde::de::DeserializeOwned + Clone + Send + 'static,
+{
+    /// Listen for TCP connections on the address of `auth`, with its access
+    /// control.
+    ///
+    /// May be called several times to listen on several addresses.
+    pub fn auth(mut self, auth: AccessControl) -> Self {
+        self.auths.push(auth);
+        self
+    }
+
+    /// Accept connections from `listener`, with the access control `auth`.
+    ///
+    /// The address in `auth` is not used for binding. May be called several
+    /// times to listen on several listeners.
+    pub fn listener(mut self, listener: Listener, auth: AccessControl) -> Self {
+        self.listeners.push((listener, auth));
+        self
+    }
+
+    /// Set the path of the event stream. Defaults to `/events`.
+    pub fn events_path(mut self, events_path: &str) -> Self {
+        self.events_path = events_path.to_string();
+        self
+    }
+
+    /// Set the name of the events carrying the state.
+    ///
+    /// Defaults to none, in which case browsers receive them as `message`
+    /// events.
+    pub fn event_name(mut self, event_name: &str) -> Self {
+        self.event_name = Some(event_name.to_string());
+        self
+    }
+
+    /// Set a function computing the value each connection is sent.
+    ///
+    /// Rather than the shared state, each connection is sent the value
+    /// returned by `view` given the shared state and information about the
+    /// connection. This is computed for each connection on every change, and
+    /// a message is only sent if the value for a connection changed.
+    ///
+    /// Events missed by reconnecting clients are not replayed when a view is
+    /// set. Instead, the current value is sent.
+    pub fn view<V, F>(mut self, view: F) -> Self
+    where
+        F: Fn(&T, &ViewContext<'_>) -> V + Send + Sync + 'static,
+        V: Serialize,
+    {
+        self.view = Some(crate::highlevel::view_fn(view));
+        self
+    }
+
+    /// Set a handler for requests not otherwise handled.
+    pub fn raw_req_handler(mut self, raw_req_handler: RawReqHandler) -> Self {
+        self.raw_req_handler = Some(raw_req_handler);
+        self
+    }
+
+    /// Set the handler for callbacks from the browser. Required.
+    ///
+    /// This is either a [CallbackHandler](crate::CallbackHandler) or, to send a response to the
+    /// client, a [CallbackHandlerWithResponse].
+    pub fn callback_handler<H>(mut self, callback_handler: H) -> Self
+    where
+        H: 'static + CallbackHandlerWithResponse<Data = CB>,
+    {
+        self.callback_handler = Some(Box::new(callback_handler));
+        self
+    }
+
+    /// Set the number of new event stream connections queued before
+    /// blocking. Defaults to `Config::channel_size`.
+    pub fn new_connection_channel_size(mut self, size: usize) -> Self {
+        self.new_connection_channel_size = Some(size);
+        self
+    }
+
+    /// Set the number of [ConnectionEvent]s queued in
+    /// [BuiApp::connection_events] before blocking. Defaults to
+    /// `Config::channel_size`.
+    pub fn connection_event_channel_size(mut self, size: usize) -> Self {
+        self.connection_event_channel_size = Some(size);
+        self
+    }
+
+    /// Start a graceful shutdown (see [BuiAppInner::shutdown]) when `signal`
+    /// completes.
+    pub fn shutdown_signal<F>(mut self, signal: F) -> Self
+    where
+        F: 'static + Future<Output = ()> + Send,
+    {
+        self.shutdown_signal = Some(Box::pin(signal));
+        self
+    }
+
+    /// Set the runtime on which to run the server. Defaults to the current
+    /// runtime.
+    pub fn handle(mut self, handle: tokio::runtime::Handle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
+    /// Check the settings, bind the addresses and start serving.
+    ///
+    /// Returns [Error::Builder] if the settings are invalid.
+    pub async fn build(self) -> Result<BuiApp<T, CB>, Error> {
+        let callback_handler = self
+            .callback_handler
+            .ok_or_else(|| Error::Builder("no callback handler was set".into()))?;
+        if self.auths.is_empty() && self.listeners.is_empty() {
+            return Err(Error::Builder(
+                "no address or listener was set to serve on".into(),
+            ));
+        }
+        if !self.events_path.starts_with('/') || self.events_path == "/" {
+            return Err(Error::Builder(format!(
+                "events path {:?} must start with '/' and not be the root",
+                self.events_path
+            )));
+        }
+        if !self.config.base_path.starts_with('/') {
+            return Err(Error::Builder(format!(
+                "base path {:?} must start with '/'",
+                self.config.base_path
+            )));
+        }
+        if ["/callback", "/login", "/logout"].contains(&self.events_path.as_str()) {
+            return Err(Error::Builder(format!(
+                "events path must not be {}",
+                self.events_path
+            )));
+        }
+        if self.config.overflow_policy == OverflowPolicy::DropOldest
+            && self.config.update_mode != UpdateMode::FullState
+        {
+            // A dropped patch would leave the client with a wrong state.
+            return Err(Error::Builder(
+                "overflow policy DropOldest requires update mode FullState".into(),
+            ));
+        }
+        let new_connection_channel_size = self
+            .new_connection_channel_size
+            .unwrap_or(self.config.channel_size);
+        let connection_event_channel_size = self
+            .connection_event_channel_size
+            .unwrap_or(self.config.channel_size);
+        if new_connection_channel_size == 0 || connection_event_channel_size == 0 {
+            return Err(Error::Builder("channel sizes must be non-zero".into()));
+        }
+
+        let mut listeners = Vec::with_capacity(self.auths.len() + self.listeners.len());
+        for auth in self.auths {
+            listeners.push((Listener::bind_tcp(*auth.bind_addr()).await?, auth));
+        }
+        listeners.extend(self.listeners);
+
+        let handle = self.handle.unwrap_or_else(tokio::runtime::Handle::current);
+
+        let shutdown_rx = self.shutdown_signal.map(|signal| {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            handle.spawn(async move {
+                signal.await;
+                let _ = tx.send(());
+            });
+            rx
+        });
+
+        let (rx_conn, bui_server) = crate::lowlevel::launch(
+            self.config,
+            &listeners[0].1,
+            new_connection_channel_size,
+            &self.events_path,
+            self.raw_req_handler,
+            callback_handler,
+        );
+
+        let auth = listeners[0].1.clone();
+        let (connection_events, inner) = crate::highlevel::start(
+            handle,
+            shutdown_rx,
+            auth,
+            self.shared_arc,
+            self.event_name,
+            self.view,
+            rx_conn,
+            bui_server,
+            listeners,
+            connection_event_channel_size,
+        )
+        .await?;
+
+        Ok(BuiApp {
+            inner,
+            connection_events,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lowlevel::tests::{runtime, test_config, NoopHandler};
+
+    /// A builder with valid settings.
+    fn builder(config: Config) -> BuiAppBuilder<u8, u8> {
+        let shared_arc = Arc::new(RwLock::new(ChangeTracker::new(0)));
+        let addr = "127.0.0.1:0".parse().unwrap();
+        BuiApp::builder(config, shared_arc)
+            .auth(AccessControl::Insecure(addr))
+            .callback_handler(NoopHandler)
+    }
+
+    /// The message of the [Error::Builder] returned by `build()`.
+    fn build_error(builder: BuiAppBuilder<u8, u8>) -> String {
+        match futures::executor::block_on(builder.build()) {
+            Err(Error::Builder(msg)) => msg,
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("settings were accepted"),
+        }
+    }
+
+    #[test]
+    fn build_accepts_valid_settings() {
+        runtime().block_on(async {
+            let app = builder(test_config()).build().await.unwrap();
+            app.inner.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn build_requires_callback_handler() {
+        let shared_arc = Arc::new(RwLock::new(ChangeTracker::new(0u8)));
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let builder = BuiApp::<u8, u8>::builder(test_config(), shared_arc)
+            .auth(AccessControl::Insecure(addr));
+        assert_eq!(build_error(builder), "no callback handler was set");
+    }
+
+    #[test]
+    fn build_requires_listener() {
+        let shared_arc = Arc::new(RwLock::new(ChangeTracker::new(0u8)));
+        let builder = BuiApp::builder(test_config(), shared_arc).callback_handler(NoopHandler);
+        assert_eq!(
+            build_error(builder),
+            "no address or listener was set to serve on"
+        );
+    }
+
+    #[test]
+    fn build_rejects_events_paths() {
+        for events_path in ["events", "/", ""] {
+            let msg = build_error(builder(test_config()).events_path(events_path));
+            assert!(msg.contains("must start with '/'"), "{}", msg);
+        }
+        for events_path in ["/callback", "/login", "/logout"] {
+            let msg = build_error(builder(test_config()).events_path(events_path));
+            assert_eq!(msg, format!("events path must not be {}", events_path));
+        }
+    }
+
+    #[test]
+    fn build_rejects_base_path() {
+        let mut config = test_config();
+        config.base_path = "bui".into();
+        assert_eq!(
+            build_error(builder(config)),
+            "base path \"bui\" must start with '/'"
+        );
+    }
+
+    #[test]
+    fn build_rejects_drop_oldest_with_patches() {
+        for update_mode in [UpdateMode::JsonPatch, UpdateMode::MergePatch] {
+            let mut config = test_config();
+            config.overflow_policy = OverflowPolicy::DropOldest;
+            config.update_mode = update_mode;
+            assert_eq!(
+                build_error(builder(config)),
+                "overflow policy DropOldest requires update mode FullState"
+            );
+        }
+    }
+
+    #[test]
+    fn build_rejects_zero_channel_sizes() {
+        const MSG: &str = "channel sizes must be non-zero";
+        let mut config = test_config();
+        config.channel_size = 0;
+        assert_eq!(build_error(builder(config)), MSG);
+        assert_eq!(
+            build_error(builder(test_config()).new_connection_channel_size(0)),
+            MSG
+        );
+        assert_eq!(
+            build_error(builder(test_config()).connection_event_channel_size(0)),
+            MSG
+        );
+    }
+}
@@ -18,4 +18,49 @@ pub enum Error {
     /// An error that occurred with an event stream.
     #[error("rx event")]
     RxEvent,
+
+    /// A path which does not start with the event stream path prefix
+    #[error("path `{0}` does not start with the events prefix")]
+    NotAnEventsPath(String),
+
+    /// A path at which a state stream is already served
+    #[error("a state stream is already served at `{0}`")]
+    DuplicateEventsPath(String),
+
+    /// Accepting a connection failed
+    ///
+    /// The server keeps running and retries, with a delay if the error is
+    /// not specific to a single connection (e.g. when out of file
+    /// descriptors).
+    #[error("failed to accept connection `{0}`")]
+    Accept(#[source] std::io::Error),
+
+    /// Invalid settings were given to `BuiAppBuilder`
+    #[error("invalid BuiApp settings: {0}")]
+    Builder(String),
+
+    /// No listener was given to serve on
+    #[error("no listener to serve on")]
+    NoListeners,
+
+    /// No access control with a pre-shared token to rotate
+    #[error("no pre-shared token to rotate")]
+    NoPreSharedToken,
+
+    /// An invalid htpasswd file
+    #[error("htpasswd error: {0}")]
+    Htpasswd(String),
+
+    /// An error with the TLS configuration
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    /// Serving an HTTP connection failed
+    #[error("failed to serve connection from {remote_addr} `{source}`")]
+    ServeConnection {
+        /// The address of the client.
+        remote_addr: crate::listener::Address,
+        /// The underlying error.
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
 }
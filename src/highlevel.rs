@@ -1,23 +1,29 @@
 //! Helpers for writing browser user interfaces (BUIs).
-use crate::lowlevel::{BuiService, EventChunkSender};
-use bui_backend_types::{ConnectionKey, SessionKey};
+use crate::lowlevel::{
+    event_stream_chunk_to_websocket, websocket_event, BuiService, Config, EventChunkSender,
+    EventStreamCloser, OverflowPolicy, UpdateMode, WebSocketTasks,
+};
+use bui_backend_types::{ConnectionKey, Encoding, SessionKey};
 
 use async_change_tracker::ChangeTracker;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::sync::Arc;
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use uuid::Uuid;
 
 use serde::Serialize;
 
 use bui_backend_types::AccessToken;
 
-use crate::access_control;
+use crate::access_control::{self, SharedAccess};
+use crate::listener::{Address, Listener};
 use crate::lowlevel::NewEventStreamConnection;
+use crate::session::SessionInfo;
 use crate::Error;
 
 // ------
@@ -46,19 +52,854 @@ pub struct ConnectionEvent {
 
 // ------
 
+/// A comment line sent to keep idle event streams open.
+const HEARTBEAT_MSG: &str = ":\n\n";
+
+/// The format of the messages sent to a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// Event stream chunks, which are also kept in the event history.
+    EventStream,
+    /// WebSocket messages in an encoding, see `WebSocketSender`.
+    WebSocket(Encoding),
+}
+
+impl Format {
+    fn of(conn_info: &NewEventStreamConnection) -> Self {
+        match conn_info.websocket {
+            Some(ref websocket) => Format::WebSocket(websocket.encoding),
+            None => Format::EventStream,
+        }
+    }
+
+    /// Encode an event.
+    fn event<V: Serialize>(
+        self,
+        value: &V,
+        event_name: Option<&str>,
+        id: Option<&str>,
+    ) -> hyper::body::Bytes {
+        match self {
+            Format::EventStream => create_event_source_msg(value, event_name, id).into(),
+            Format::WebSocket(encoding) => {
+                let event_name = event_name.unwrap_or("message");
+                websocket_event(encoding, id, event_name, value)
+                    .expect("encode")
+                    .into()
+            }
+        }
+    }
+
+    /// The message sent to keep an idle connection open.
+    fn heartbeat(self) -> hyper::body::Bytes {
+        match self {
+            Format::EventStream => HEARTBEAT_MSG.into(),
+            // Sent as a ping.
+            Format::WebSocket(_) => hyper::body::Bytes::new(),
+        }
+    }
+
+    /// The event sent to each connection before closing it upon shutdown.
+    fn going_away(self) -> hyper::body::Bytes {
+        self.event(
+            &serde_json::Map::new(),
+            Some(bui_backend_types::GOING_AWAY_EVENT),
+            None,
+        )
+    }
+}
+
+/// An event, encoded at most once for each format.
+struct EncodedEvent<F> {
+    encode: F,
+    encoded: Vec<(Format, hyper::body::Bytes)>,
+}
+
+impl<F> EncodedEvent<F>
+where
+    F: FnMut(Format) -> hyper::body::Bytes,
+{
+    fn new(encode: F) -> Self {
+        Self {
+            encode,
+            encoded: Vec::new(),
+        }
+    }
+
+    fn get(&mut self, format: Format) -> hyper::body::Bytes {
+        if let Some((_, msg)) = self.encoded.iter().find(|(f, _)| *f == format) {
+            return msg.clone();
+        }
+        let msg = (self.encode)(format);
+        self.encoded.push((format, msg.clone()));
+        msg
+    }
+}
+
+/// An event stream connection.
+struct Connection {
+    session_key: SessionKey,
+    format: Format,
+    outbox: Arc<Outbox>,
+    closer: EventStreamCloser,
+    path: String,
+    /// The value most recently sent to this connection if a view is set.
+    last_view: Option<serde_json::Value>,
+}
+
+type Txers = HashMap<ConnectionKey, Connection>;
+
+/// Events queued for sending to a single connection.
+///
+/// The broadcaster adds events without waiting while a task per connection
+/// forwards them to the event stream.
+#[derive(Default)]
+struct Outbox {
+    state: Mutex<OutboxState>,
+    notify: tokio::sync::Notify,
+}
+
+#[derive(Default)]
+struct OutboxState {
+    queue: VecDeque<hyper::body::Bytes>,
+    closed: bool,
+    /// Close the event stream once the queue is empty.
+    finishing: bool,
+}
+
+impl Outbox {
+    /// Queue a chunk, applying `policy` if `capacity` chunks are queued.
+    ///
+    /// Returns `false` if the connection was closed and should be removed.
+    fn push<F>(
+        &self,
+        chunk: hyper::body::Bytes,
+        capacity: usize,
+        policy: OverflowPolicy,
+        snapshot: F,
+    ) -> bool
+    where
+        F: FnOnce() -> hyper::body::Bytes,
+    {
+        {
+            let mut state = self.state.lock();
+            if state.closed {
+                return false;
+            }
+            if state.queue.len() >= capacity {
+                match policy {
+                    OverflowPolicy::DropOldest => {
+                        state.queue.pop_front();
+                        state.queue.push_back(chunk);
+                    }
+                    OverflowPolicy::CoalesceToLatest => {
+                        state.queue.clear();
+                        state.queue.push_back(snapshot());
+                    }
+                    OverflowPolicy::Disconnect => {
+                        state.closed = true;
+                    }
+                }
+            } else {
+                state.queue.push_back(chunk);
+            }
+        }
+        self.notify.notify_one();
+        !self.state.lock().closed
+    }
+
+    /// Queue a heartbeat, unless chunks are queued already.
+    ///
+    /// Returns `false` if the connection was closed and should be removed.
+    fn heartbeat(&self, chunk: hyper::body::Bytes) -> bool {
+        {
+            let mut state = self.state.lock();
+            if state.closed {
+                return false;
+            }
+            if !state.queue.is_empty() {
+                // The connection is not idle.
+                return true;
+            }
+            state.queue.push_back(chunk);
+        }
+        self.notify.notify_one();
+        true
+    }
+
+    /// Queue a final chunk, after which the event stream is closed.
+    fn finish(&self, chunk: hyper::body::Bytes) {
+        {
+            let mut state = self.state.lock();
+            state.queue.push_back(chunk);
+            state.finishing = true;
+        }
+        self.notify.notify_one();
+    }
+
+    /// Stop forwarding.
+    fn close(&self) {
+        self.state.lock().closed = true;
+        self.notify.notify_one();
+    }
+
+    /// Forward queued chunks to the event stream, or the messages to the
+    /// WebSocket, until closed.
+    ///
+    /// While waiting for chunks, this also watches for the client closing
+    /// the connection, so that idle connections do not keep it running.
+    async fn forward(self: Arc<Self>, tx: EventChunkSender, closer: EventStreamCloser) {
+        loop {
+            let next = {
+                let mut state = self.state.lock();
+                if state.closed {
+                    return;
+                }
+                let next = state.queue.pop_front();
+                if next.is_none() && state.finishing {
+                    closer.close();
+                    return;
+                }
+                next
+            };
+            match next {
+                Some(chunk) => {
+                    if let Err(e) = tx.send(chunk).await {
+                        info!(
+                            "Failed to send data to event stream, client \
+                                probably disconnected. {:?}",
+                            e
+                        );
+                        self.close();
+                        return;
+                    }
+                }
+                None => {
+                    let notified = Box::pin(self.notify.notified());
+                    let closed = Box::pin(tx.closed());
+                    if let futures::future::Either::Right(_) =
+                        futures::future::select(notified, closed).await
+                    {
+                        info!("Event stream closed, client probably disconnected.");
+                        self.close();
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Information about a connection, passed to the view function.
+#[derive(Debug, Clone, Copy)]
+pub struct ViewContext<'a> {
+    /// Identifier for the connected session (one per browser).
+    pub session_key: SessionKey,
+    /// Identifier for the connection (one per tab).
+    pub connection_key: ConnectionKey,
+    /// The path being requested (starts with `BuiService::events_prefix`).
+    pub path: &'a str,
+}
+
+/// Computes the value a connection is sent, see [crate::BuiAppBuilder::view].
+pub(crate) type ViewFn<T> = Arc<dyn Fn(&T, &ViewContext<'_>) -> serde_json::Value + Send + Sync>;
+
+/// Wrap `view` into a [ViewFn].
+pub(crate) fn view_fn<T, V, F>(view: F) -> ViewFn<T>
+where
+    F: Fn(&T, &ViewContext<'_>) -> V + Send + Sync + 'static,
+    V: Serialize,
+{
+    Arc::new(move |value, ctx| serde_json::to_value(view(value, ctx)).expect("encode"))
+}
+
+type ErrorFn = Arc<dyn Fn(Error) + Send + Sync>;
+
+/// How often sessions which have expired are forgotten.
+const PRUNE_SESSIONS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The longest delay before accepting connections again after an error.
+const MAX_ACCEPT_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The longest time a client may take to complete the TLS handshake.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The events sent to all connections.
+///
+/// Event ids have the form `<epoch>-<n>` where `n` increases with each event
+/// and `epoch` is chosen randomly on startup so that ids from a previous run
+/// of the server are not mistaken for current ones.
+struct EventHistory {
+    /// The most recently sent value, which is the base of subsequent patches.
+    last_value: serde_json::Value,
+    epoch: String,
+    /// The number of the most recently sent event.
+    last_n: u64,
+    /// Recently sent events, kept to resend to reconnecting clients.
+    buffer: VecDeque<(u64, hyper::body::Bytes)>,
+    /// Whether each event carries the full state, such that only the most
+    /// recent one is needed to catch up.
+    full_state: bool,
+}
+
+impl EventHistory {
+    fn new(last_value: serde_json::Value, full_state: bool) -> Self {
+        Self {
+            last_value,
+            epoch: format!("{:08x}", Uuid::new_v4().as_u128() as u32),
+            last_n: 0,
+            buffer: VecDeque::new(),
+            full_state,
+        }
+    }
+
+    fn last_event_id(&self) -> String {
+        format!("{}-{}", self.epoch, self.last_n)
+    }
+
+    fn next_event_id(&mut self) -> String {
+        self.last_n += 1;
+        self.last_event_id()
+    }
+
+    /// Store the most recent event, keeping at most `size` events.
+    ///
+    /// Of events carrying the full state, only the most recent is kept.
+    fn push(&mut self, chunk: hyper::body::Bytes, size: usize) {
+        let size = if self.full_state { size.min(1) } else { size };
+        self.buffer.push_back((self.last_n, chunk));
+        while self.buffer.len() > size {
+            self.buffer.pop_front();
+        }
+    }
+
+    /// Get all events sent after the event with id `last_event_id`.
+    ///
+    /// If events carry the full state, this is only the most recent event.
+    /// Returns `None` if the events are not available.
+    fn events_since(&self, last_event_id: &str) -> Option<hyper::body::Bytes> {
+        let mut parts = last_event_id.splitn(2, '-');
+        if parts.next() != Some(self.epoch.as_str()) {
+            return None;
+        }
+        let n: u64 = parts.next()?.parse().ok()?;
+        if n > self.last_n {
+            return None;
+        }
+        if self.full_state && n < self.last_n {
+            return match self.buffer.back() {
+                Some((i, chunk)) if *i == self.last_n => Some(chunk.clone()),
+                _ => None,
+            };
+        }
+        let missed: Vec<_> = self
+            .buffer
+            .iter()
+            .filter(|(i, _)| *i > n)
+            .map(|(_, chunk)| chunk)
+            .collect();
+        if missed.len() as u64 != self.last_n - n {
+            // Some of the missed events are no longer in the buffer.
+            return None;
+        }
+        Some(
+            missed
+                .into_iter()
+                .flatten()
+                .copied()
+                .collect::<Vec<u8>>()
+                .into(),
+        )
+    }
+}
+
+/// Settings for pushing state to event stream connections.
+#[derive(Clone)]
+struct StreamSettings {
+    update_mode: UpdateMode,
+    replay_buffer_size: usize,
+    heartbeat_interval: Option<std::time::Duration>,
+    /// The `retry` field sent at the start of each connection.
+    retry_msg: Option<String>,
+    overflow_policy: OverflowPolicy,
+    /// The number of chunks queued for each connection.
+    queue_size: usize,
+}
+
+impl StreamSettings {
+    fn new(config: &Config) -> Self {
+        Self {
+            update_mode: config.update_mode,
+            replay_buffer_size: config.replay_buffer_size,
+            heartbeat_interval: config.heartbeat_interval,
+            retry_msg: config
+                .retry_interval
+                .map(|retry| format!("retry: {}\n\n", retry.as_millis())),
+            overflow_policy: config.overflow_policy,
+            queue_size: config.channel_size.max(1),
+        }
+    }
+}
+
+/// Pushes a shared state, and changes to it, to event stream connections.
+struct StateStream<T> {
+    shared_arc: Arc<RwLock<ChangeTracker<T>>>,
+    event_name: Option<String>,
+    patch_event_name: String,
+    settings: StreamSettings,
+    txers: RwLock<Txers>,
+    history: Mutex<EventHistory>,
+    /// Set upon creation, so that no connection is sent the unprojected
+    /// state.
+    view: Option<ViewFn<T>>,
+    shutdown: watch::Receiver<bool>,
+    /// Runs the tasks forwarding events to each connection.
+    handle: tokio::runtime::Handle,
+}
+
+/// A type-erased `StateStream`, to which connections can be added.
+trait EventStream: Send + Sync {
+    /// Add a connection, sending it the initial value.
+    fn connect(&self, conn_info: &NewEventStreamConnection);
+    /// Send the going away event to all connections and close them.
+    fn go_away(&self);
+}
+
+impl<T> StateStream<T>
+where
+    T: Clone + Serialize + 'static + Send + Sync,
+{
+    /// Create the stream and spawn the tasks pushing to its connections.
+    fn spawn(
+        handle: &tokio::runtime::Handle,
+        shared_arc: Arc<RwLock<ChangeTracker<T>>>,
+        event_name: Option<String>,
+        settings: StreamSettings,
+        view: Option<ViewFn<T>>,
+        new_conn_tx: mpsc::Sender<ConnectionEvent>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Arc<Self> {
+        // Listen for changes and keep the most recently broadcast value, which
+        // is the base of patches. Doing both while holding the lock ensures no
+        // change is missed.
+        let (rx, history) = {
+            let shared = shared_arc.write();
+            let last_value = serde_json::to_value(shared.as_ref()).expect("encode");
+            let full_state = matches!(settings.update_mode, UpdateMode::FullState);
+            (
+                shared.get_changes(10), // capacity of channel is 10 changes
+                EventHistory::new(last_value, full_state),
+            )
+        };
+
+        let stream = Arc::new(Self {
+            shared_arc,
+            patch_event_name: bui_backend_types::patch_event_name(event_name.as_deref()),
+            event_name,
+            settings,
+            txers: RwLock::new(HashMap::new()),
+            history: Mutex::new(history),
+            view,
+            shutdown,
+            handle: handle.clone(),
+        });
+
+        handle.spawn(stream.clone().push_changes(rx, new_conn_tx.clone()));
+        if let Some(heartbeat_interval) = stream.settings.heartbeat_interval {
+            handle.spawn(
+                stream
+                    .clone()
+                    .send_heartbeats(heartbeat_interval, new_conn_tx),
+            );
+        }
+        stream
+    }
+
+    /// Send changes of the shared state to all connections.
+    ///
+    /// This never waits on a connection. Events are queued for each
+    /// connection and the overflow policy is applied to slow clients.
+    async fn push_changes(
+        self: Arc<Self>,
+        rx: futures::channel::mpsc::Receiver<(T, T)>,
+        new_conn_tx: mpsc::Sender<ConnectionEvent>,
+    ) {
+        let mut rx =
+            futures::StreamExt::take_until(rx, Box::pin(shutdown_started(self.shutdown.clone())));
+        let update_mode = self.settings.update_mode;
+        let overflow_policy = self.settings.overflow_policy;
+        let queue_size = self.settings.queue_size;
+        let event_name = self.event_name.as_deref();
+        let patch_event_name = self.patch_event_name.as_str();
+
+        while let Some((_old, new_value)) = futures::StreamExt::next(&mut rx).await {
+            let view = self.view.as_ref();
+
+            // Patches are computed relative to the last value we sent rather
+            // than `_old` because the change tracker drops changes when its
+            // channel is full.
+            let new_json = match (update_mode, &view) {
+                (UpdateMode::JsonPatch | UpdateMode::MergePatch, None) => {
+                    Some(serde_json::to_value(&new_value).expect("encode"))
+                }
+                _ => None,
+            };
+
+            let removed: Vec<(ConnectionKey, Connection)>;
+            {
+                let mut sources = self.txers.write();
+                let mut history = self.history.lock();
+
+                let patch = match new_json {
+                    Some(new_json) => {
+                        let prev_json = std::mem::replace(&mut history.last_value, new_json);
+                        match compute_patch(update_mode, &prev_json, &history.last_value) {
+                            Some(patch) => Some(patch),
+                            None => {
+                                // Nothing changed, so there is nothing to send.
+                                continue;
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                let id = history.next_event_id();
+
+                // The state is encoded once for each format and the resulting
+                // `Bytes` are shared by all connections using it.
+                let mut event_msg = EncodedEvent::new(|format: Format| match patch {
+                    Some(ref patch) => format.event(patch, Some(patch_event_name), Some(&id)),
+                    None => format.event(&new_value, event_name, Some(&id)),
+                });
+                if view.is_none() {
+                    history.push(
+                        event_msg.get(Format::EventStream),
+                        self.settings.replay_buffer_size,
+                    );
+                }
+
+                // The full state, sent to slow clients instead of the queued
+                // patches.
+                let mut snapshot_msg = EncodedEvent::new(|format: Format| {
+                    format.event(&history.last_value, event_name, Some(&id))
+                });
+
+                let mut slow = vec![];
+                for (connection_key, conn) in sources.iter_mut() {
+                    let format = conn.format;
+                    let pushed = match view {
+                        Some(view) => {
+                            let ctx = ViewContext {
+                                session_key: conn.session_key,
+                                connection_key: *connection_key,
+                                path: &conn.path,
+                            };
+                            let value = view(&new_value, &ctx);
+                            let msg = match (conn.last_view.as_ref(), update_mode) {
+                                (Some(prev), UpdateMode::JsonPatch | UpdateMode::MergePatch) => {
+                                    compute_patch(update_mode, prev, &value).map(|patch| {
+                                        format.event(&patch, Some(patch_event_name), Some(&id))
+                                    })
+                                }
+                                (Some(prev), UpdateMode::FullState) if *prev == value => None,
+                                _ => Some(format.event(&value, event_name, Some(&id))),
+                            };
+                            let pushed = match msg {
+                                Some(msg) => {
+                                    conn.outbox.push(msg, queue_size, overflow_policy, || {
+                                        format.event(&value, event_name, Some(&id))
+                                    })
+                                }
+                                None => {
+                                    // This connection's value did not change.
+                                    true
+                                }
+                            };
+                            conn.last_view = Some(value);
+                            pushed
+                        }
+                        None => {
+                            let msg = event_msg.get(format);
+                            conn.outbox
+                                .push(msg.clone(), queue_size, overflow_policy, || match patch {
+                                    Some(_) => snapshot_msg.get(format),
+                                    None => msg,
+                                })
+                        }
+                    };
+                    if !pushed {
+                        slow.push(*connection_key);
+                    }
+                }
+
+                removed = slow
+                    .into_iter()
+                    .filter_map(|connection_key| {
+                        sources
+                            .remove(&connection_key)
+                            .map(|conn| (connection_key, conn))
+                    })
+                    .collect();
+            }
+
+            for (connection_key, conn) in removed {
+                info!("Closing event stream of slow or disconnected client.");
+                conn.outbox.close();
+                conn.closer.close();
+                send_disconnect(&new_conn_tx, conn.session_key, connection_key, conn.path).await;
+            }
+        }
+    }
+
+    /// Periodically send a comment line to all connections.
+    async fn send_heartbeats(
+        self: Arc<Self>,
+        heartbeat_interval: std::time::Duration,
+        new_conn_tx: mpsc::Sender<ConnectionEvent>,
+    ) {
+        let mut ticks = futures::StreamExt::take_until(
+            tokio_stream::wrappers::IntervalStream::new(tokio::time::interval(heartbeat_interval)),
+            Box::pin(shutdown_started(self.shutdown.clone())),
+        );
+        while futures::StreamExt::next(&mut ticks).await.is_some() {
+            let mut closed = vec![];
+            {
+                // Heartbeats are queued like events, so that they keep their
+                // order and closed connections are noticed.
+                let mut sources = self.txers.write();
+                sources.retain(|connection_key, conn| {
+                    if conn.outbox.heartbeat(conn.format.heartbeat()) {
+                        true
+                    } else {
+                        conn.closer.close();
+                        closed.push((conn.session_key, *connection_key, conn.path.clone()));
+                        false
+                    }
+                });
+            }
+            for (session_key, connection_key, path) in closed {
+                info!("Event stream closed, client probably disconnected.");
+                send_disconnect(&new_conn_tx, session_key, connection_key, path).await;
+            }
+        }
+    }
+}
+
+impl<T> EventStream for StateStream<T>
+where
+    T: Clone + Serialize + 'static + Send + Sync,
+{
+    fn go_away(&self) {
+        let mut sources = self.txers.write();
+        for (_, conn) in sources.drain() {
+            conn.outbox.finish(conn.format.going_away());
+        }
+    }
+
+    fn connect(&self, conn_info: &NewEventStreamConnection) {
+        let update_mode = self.settings.update_mode;
+        let event_name = self.event_name.as_deref();
+        let format = Format::of(conn_info);
+
+        // Hold the lock on txers while queueing the current value so that no
+        // change is broadcast in between. This guarantees the initial value is
+        // the base of subsequent patches.
+        let mut txer_guard = self.txers.write();
+        let history = self.history.lock();
+
+        let mut initial = vec![];
+        if let (Format::EventStream, Some(retry_msg)) = (format, &self.settings.retry_msg) {
+            initial.push(retry_msg.clone().into());
+        }
+
+        let mut last_view = None;
+
+        match self.view {
+            Some(ref view) => {
+                let ctx = ViewContext {
+                    session_key: conn_info.session_key,
+                    connection_key: conn_info.connection_key,
+                    path: &conn_info.path,
+                };
+                let value = {
+                    let shared = self.shared_arc.read();
+                    view(shared.as_ref(), &ctx)
+                };
+                let id = history.last_event_id();
+                initial.push(format.event(&value, event_name, Some(&id)));
+                last_view = Some(value);
+            }
+            None => {
+                // Send the events missed by a reconnecting client or, if these
+                // are not available, the current value. A reconnecting client
+                // which missed nothing is sent nothing.
+                let missed = conn_info
+                    .last_event_id
+                    .as_deref()
+                    .and_then(|last_event_id| history.events_since(last_event_id));
+                match (missed, format) {
+                    (Some(missed), Format::EventStream) => {
+                        if !missed.is_empty() {
+                            initial.push(missed);
+                        }
+                    }
+                    (Some(missed), Format::WebSocket(encoding)) => {
+                        initial.extend(event_stream_chunk_to_websocket(&missed, encoding));
+                    }
+                    (None, _) => {
+                        let id = history.last_event_id();
+                        let msg = match update_mode {
+                            UpdateMode::FullState => {
+                                let shared = self.shared_arc.read();
+                                format.event(shared.as_ref(), event_name, Some(&id))
+                            }
+                            UpdateMode::JsonPatch | UpdateMode::MergePatch => {
+                                format.event(&history.last_value, event_name, Some(&id))
+                            }
+                        };
+                        initial.push(msg);
+                    }
+                }
+            }
+        }
+
+        let outbox = Arc::new(Outbox::default());
+        outbox.state.lock().queue.extend(initial);
+        let tx = match conn_info.websocket {
+            Some(ref websocket) => websocket.tx.clone(),
+            None => conn_info.chunk_sender.clone(),
+        };
+        self.handle
+            .spawn(outbox.clone().forward(tx, conn_info.closer.clone()));
+        if *self.shutdown.borrow() {
+            // Shutdown started while this connection was being set up.
+            outbox.finish(format.going_away());
+            return;
+        }
+        let conn = Connection {
+            session_key: conn_info.session_key,
+            format,
+            outbox,
+            closer: conn_info.closer.clone(),
+            path: conn_info.path.clone(),
+            last_view,
+        };
+        txer_guard.insert(conn_info.connection_key, conn);
+    }
+}
+
 /// Maintain state within a BUI application.
 pub struct BuiAppInner<T, CB> {
-    i_shared_arc: Arc<RwLock<ChangeTracker<T>>>,
-    i_txers: Arc<RwLock<HashMap<ConnectionKey, (SessionKey, EventChunkSender, String)>>>,
+    i_stream: Arc<StateStream<T>>,
+    i_streams: Arc<RwLock<HashMap<String, Arc<dyn EventStream>>>>,
     i_bui_server: BuiService<CB>,
     auth: access_control::AccessControl,
-    local_addr: std::net::SocketAddr,
+    local_addrs: Vec<(Address, SharedAccess)>,
+    handle: tokio::runtime::Handle,
+    new_conn_tx: mpsc::Sender<ConnectionEvent>,
+    shutdown_tx: Arc<watch::Sender<bool>>,
+    stopped_rx: watch::Receiver<bool>,
+    error_handler: Arc<RwLock<Option<ErrorFn>>>,
 }
 
-impl<'a, T, CB> BuiAppInner<T, CB> {
+impl<T, CB> BuiAppInner<T, CB> {
     /// Get reference counted reference to the underlying data store.
     pub fn shared_arc(&self) -> &Arc<RwLock<ChangeTracker<T>>> {
-        &self.i_shared_arc
+        &self.i_stream.shared_arc
+    }
+
+    /// Set a function called with errors which occur in the server.
+    ///
+    /// These errors are also logged. The server keeps running after them.
+    pub fn set_error_handler<F>(&self, handler: F)
+    where
+        F: Fn(Error) + Send + Sync + 'static,
+    {
+        *self.error_handler.write() = Some(Arc::new(handler));
+    }
+
+    /// Serve an additional shared state to event streams opened at `path`.
+    ///
+    /// Changes to `shared_arc` are sent, with the event name `event_name`,
+    /// only to connections requesting exactly `path`, which must start with
+    /// `BuiService::events_prefix`. All other event stream paths are served
+    /// the state given to `create_bui_app_inner`. Configuration settings such
+    /// as the update mode apply to all streams. Returns
+    /// [Error::DuplicateEventsPath] if a stream is already served at `path`.
+    pub fn add_state_stream<U>(
+        &self,
+        path: &str,
+        shared_arc: Arc<RwLock<ChangeTracker<U>>>,
+        event_name: Option<String>,
+    ) -> Result<(), Error>
+    where
+        U: Clone + Serialize + 'static + Send + Sync,
+    {
+        if !path.starts_with(self.i_bui_server.events_prefix()) {
+            return Err(Error::NotAnEventsPath(path.to_string()));
+        }
+        // Hold the lock, such that no other stream is added at `path`
+        // meanwhile.
+        let mut streams = self.i_streams.write();
+        if streams.contains_key(path) {
+            return Err(Error::DuplicateEventsPath(path.to_string()));
+        }
+        let stream = StateStream::spawn(
+            &self.handle,
+            shared_arc,
+            event_name,
+            self.i_stream.settings.clone(),
+            None,
+            self.new_conn_tx.clone(),
+            self.shutdown_tx.subscribe(),
+        );
+        streams.insert(path.to_string(), stream);
+        Ok(())
+    }
+
+    /// Gracefully shut down the server.
+    ///
+    /// This stops accepting connections, sends an event named
+    /// [bui_backend_types::GOING_AWAY_EVENT] to every open event stream before
+    /// closing it, and waits up to `Config::shutdown_timeout` for requests in
+    /// progress, such as callbacks sent over HTTP or WebSockets, to complete. The returned future resolves
+    /// once the server has stopped.
+    ///
+    /// Shutdown starts when the returned future is first polled. It is also
+    /// started when the `shutdown_rx` passed to `create_bui_app_inner` fires.
+    /// Awaiting this after shutdown was started only waits for it to
+    /// complete.
+    pub fn shutdown(&self) -> impl Future<Output = ()> + Send + 'static {
+        let shutdown_tx = self.shutdown_tx.clone();
+        let stopped = self.stopped();
+        async move {
+            shutdown_tx.send_replace(true);
+            stopped.await
+        }
+    }
+
+    /// Wait until the server has stopped after shutdown.
+    pub fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut stopped_rx = self.stopped_rx.clone();
+        async move {
+            // An error means the shutdown task ended, so we are done anyway.
+            let _ = stopped_rx.wait_for(|stopped| *stopped).await;
+        }
+    }
+
+    /// List the sessions (i.e. browsers) seen by the server.
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.i_bui_server.sessions()
+    }
+
+    /// End the session `session_key`, closing its event streams immediately.
+    ///
+    /// Returns whether the session was known. See
+    /// [BuiService::revoke_session].
+    pub fn revoke_session(&self, session_key: SessionKey) -> bool {
+        self.i_bui_server.revoke_session(session_key)
     }
 
     /// Get reference to to the underlying `BuiService`.
@@ -67,24 +908,130 @@ impl<'a, T, CB> BuiAppInner<T, CB> {
     }
 
     /// Get our local IP address.
+    ///
+    /// This is the address of the first TCP listener. Without one, such as
+    /// when serving only a Unix domain socket, this is the address of the
+    /// `AccessControl`. See [BuiAppInner::local_address] for the address of
+    /// any listener.
     pub fn local_addr(&self) -> &std::net::SocketAddr {
-        &self.local_addr
+        self.local_addrs
+            .iter()
+            .find_map(|(addr, _)| match addr {
+                Address::Tcp(addr) => Some(addr),
+                Address::Unix(_) => None,
+            })
+            .unwrap_or_else(|| self.auth.bind_addr())
+    }
+
+    /// Get the address on which we accept connections.
+    ///
+    /// With several listeners, this is the address of the first. Without a
+    /// listener (see [create_bui_app_inner_without_listener]), this is `None`.
+    pub fn local_address(&self) -> Option<&Address> {
+        self.local_addrs.first().map(|(addr, _)| addr)
+    }
+
+    /// Get the addresses on which we accept connections, in the order the
+    /// listeners were given.
+    pub fn local_addrs(&self) -> impl Iterator<Item = &Address> {
+        self.local_addrs.iter().map(|(addr, _)| addr)
     }
 
     /// Get our access token.
+    ///
+    /// With several listeners, this is the token of the first. This is
+    /// `AccessToken::NoToken` when authenticating with an authenticator
+    /// other than a pre-shared token.
     pub fn token(&self) -> AccessToken {
-        self.auth.token()
+        match self.local_addrs.first() {
+            Some((_, access)) => access.token(),
+            None => self.i_bui_server.access().token(),
+        }
+    }
+
+    /// The access controls in effect: that of the `BuiService` and those
+    /// of the listeners.
+    fn accesses(&self) -> impl Iterator<Item = &SharedAccess> {
+        std::iter::once(self.i_bui_server.access())
+            .chain(self.local_addrs.iter().map(|(_, access)| access))
+    }
+
+    /// Replace the pre-shared token of the listeners which have one.
+    ///
+    /// New sessions must be started with `token`. Sessions already started
+    /// continue; to end them too, see [BuiAppInner::rotate_jwt_secret].
+    /// Access controlled by other authenticators is not changed. Returns
+    /// [Error::NoPreSharedToken] if no listener has a pre-shared token.
+    pub fn rotate_token(&self, token: &str) -> Result<(), Error> {
+        let mut any = false;
+        for access in self.accesses() {
+            any |= access.set_token(token);
+        }
+        if any {
+            Ok(())
+        } else {
+            Err(Error::NoPreSharedToken)
+        }
+    }
+
+    /// Sign session cookies with `secret` from now on.
+    ///
+    /// With a `grace` period, cookies signed with the previous secrets are
+    /// accepted for that long, and are replaced by cookies signed with
+    /// `secret` when used. Sessions in use therefore continue. With `None`,
+    /// existing sessions end immediately: their cookies are rejected and
+    /// their event streams closed.
+    pub fn rotate_jwt_secret(&self, secret: &[u8], grace: Option<std::time::Duration>) {
+        for access in self.accesses() {
+            access.rotate_jwt_secret(secret, grace.unwrap_or_default());
+        }
+        if grace.is_none() {
+            self.i_bui_server.revoke_all_sessions();
+        }
     }
 
     /// Attempt to get our URL.
     ///
     /// This may fail if, for example, the locally known IP address is
-    /// not the IP address that users will connect to.
+    /// not the IP address that users will connect to. When listening on a
+    /// Unix domain socket, the host is given as `localhost`.
+    ///
+    /// With several listeners, this is the URL of the first. Without a
+    /// listener, the address of the access control is used.
     pub fn guess_url_with_token(&self) -> String {
-        match self.auth.token() {
-            AccessToken::NoToken => format!("http://{}", self.local_addr),
+        match self.local_addrs.first() {
+            Some((addr, access)) => self.guess_url(addr, access),
+            None => self.guess_url(
+                &Address::Tcp(*self.auth.bind_addr()),
+                self.i_bui_server.access(),
+            ),
+        }
+    }
+
+    /// Attempt to get the URL of each listener, in the order the listeners
+    /// were given.
+    pub fn guess_urls_with_token(&self) -> Vec<String> {
+        self.local_addrs
+            .iter()
+            .map(|(addr, access)| self.guess_url(addr, access))
+            .collect()
+    }
+
+    fn guess_url(&self, addr: &Address, access: &SharedAccess) -> String {
+        let scheme = match self.i_bui_server.config().tls {
+            Some(_) => "https",
+            None => "http",
+        };
+        let host = match addr {
+            Address::Tcp(addr) => addr.to_string(),
+            Address::Unix(_) => "localhost".to_string(),
+        };
+        let base_path = self.i_bui_server.base_path();
+        match access.token() {
+            AccessToken::NoToken if base_path.is_empty() => format!("{}://{}", scheme, host),
+            AccessToken::NoToken => format!("{}://{}{}/", scheme, host, base_path),
             AccessToken::PreSharedToken(ref tok) => {
-                format!("http://{}/?token={}", self.local_addr, tok)
+                format!("{}://{}{}/?token={}", scheme, host, base_path, tok)
             }
         }
     }
@@ -115,16 +1062,192 @@ pub fn generate_auth_with_token(
     Ok(access_control::AccessControl::WithToken(info))
 }
 
+/// Return access control information given an authenticator and a JWT
+/// secret.
+///
+/// Browsers without a session must present credentials accepted by
+/// `authenticator`.
+pub fn generate_auth_with_authenticator<A>(
+    addr: std::net::SocketAddr,
+    secret: Vec<u8>,
+    authenticator: A,
+) -> access_control::AccessControl
+where
+    A: crate::auth::Authenticator + 'static,
+{
+    let info =
+        access_control::AccessInfo::with_authenticator(addr, Arc::new(authenticator), secret);
+    access_control::AccessControl::WithToken(info)
+}
+
 /// Factory function to create a new BUI application.
-pub async fn create_bui_app_inner<'a, T, CB>(
+///
+/// This listens for TCP connections on the address given by `auth`. To listen
+/// otherwise, use [create_bui_app_inner_with_listener].
+pub async fn create_bui_app_inner<T, CB>(
     handle: tokio::runtime::Handle,
-    mut shutdown_rx: Option<tokio::sync::oneshot::Receiver<()>>,
+    shutdown_rx: Option<tokio::sync::oneshot::Receiver<()>>,
+    auth: &access_control::AccessControl,
+    shared_arc: Arc<RwLock<ChangeTracker<T>>>,
+    event_name: Option<String>,
+    rx_conn: mpsc::Receiver<NewEventStreamConnection>,
+    bui_server: BuiService<CB>,
+) -> Result<(mpsc::Receiver<ConnectionEvent>, BuiAppInner<T, CB>), Error>
+where
+    T: Clone + Serialize + 'static + Send + Sync,
+    CB: serde::de::DeserializeOwned + Clone + Send + 'static,
+{
+    let listener = Listener::bind_tcp(*auth.bind_addr()).await?;
+    create_bui_app_inner_with_listener(
+        handle,
+        shutdown_rx,
+        auth,
+        shared_arc,
+        event_name,
+        rx_conn,
+        bui_server,
+        listener,
+    )
+    .await
+}
+
+/// Factory function to create a new BUI application accepting connections
+/// from `listener`.
+///
+/// This allows serving on a Unix domain socket or on a listener bound
+/// elsewhere, such as one passed by systemd socket activation (see
+/// [Listener::from_systemd]). The address in `auth` is not used for binding.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_bui_app_inner_with_listener<T, CB>(
+    handle: tokio::runtime::Handle,
+    shutdown_rx: Option<tokio::sync::oneshot::Receiver<()>>,
+    auth: &access_control::AccessControl,
+    shared_arc: Arc<RwLock<ChangeTracker<T>>>,
+    event_name: Option<String>,
+    rx_conn: mpsc::Receiver<NewEventStreamConnection>,
+    bui_server: BuiService<CB>,
+    listener: Listener,
+) -> Result<(mpsc::Receiver<ConnectionEvent>, BuiAppInner<T, CB>), Error>
+where
+    T: Clone + Serialize + 'static + Send + Sync,
+    CB: serde::de::DeserializeOwned + Clone + Send + 'static,
+{
+    create_bui_app_inner_with_listeners(
+        handle,
+        shutdown_rx,
+        shared_arc,
+        event_name,
+        rx_conn,
+        bui_server,
+        vec![(listener, auth.clone())],
+    )
+    .await
+}
+
+/// Factory function to create a new BUI application accepting connections
+/// from several listeners.
+///
+/// Each listener has its own access control, so that, for example, the
+/// loopback interface can be served without a token while another interface
+/// requires one. Requests from all listeners share the same state, sessions
+/// and connections. The first listener is used for
+/// [BuiAppInner::local_address], [BuiAppInner::token] and
+/// [BuiAppInner::guess_url_with_token].
+///
+/// The address in each `AccessControl` is not used for binding. To bind it,
+/// use [Listener::bind_tcp] with [AccessControl::bind_addr].
+///
+/// Returns [Error::NoListeners] if `listeners` is empty.
+///
+/// [AccessControl::bind_addr]: crate::AccessControl::bind_addr
+pub async fn create_bui_app_inner_with_listeners<T, CB>(
+    handle: tokio::runtime::Handle,
+    shutdown_rx: Option<tokio::sync::oneshot::Receiver<()>>,
+    shared_arc: Arc<RwLock<ChangeTracker<T>>>,
+    event_name: Option<String>,
+    rx_conn: mpsc::Receiver<NewEventStreamConnection>,
+    bui_server: BuiService<CB>,
+    listeners: Vec<(Listener, access_control::AccessControl)>,
+) -> Result<(mpsc::Receiver<ConnectionEvent>, BuiAppInner<T, CB>), Error>
+where
+    T: Clone + Serialize + 'static + Send + Sync,
+    CB: serde::de::DeserializeOwned + Clone + Send + 'static,
+{
+    let auth = match listeners.first() {
+        Some((_, auth)) => auth.clone(),
+        None => return Err(Error::NoListeners),
+    };
+    start(
+        handle,
+        shutdown_rx,
+        auth,
+        shared_arc,
+        event_name,
+        None,
+        rx_conn,
+        bui_server,
+        listeners,
+        5,
+    )
+    .await
+}
+
+/// Factory function to create a new BUI application which does not accept
+/// connections itself.
+///
+/// Instead, another server passes requests to the [BuiService], which
+/// implements `tower_service::Service`. For example, to mount the BUI under
+/// an [axum](https://crates.io/crates/axum) application:
+///
+/// ```ignore
+/// let app = axum::Router::new()
+///     .route("/status", axum::routing::get(status))
+///     .nest_service("/bui", inner.bui_service().clone());
+/// ```
+///
+/// The state is pushed to event streams as when serving a listener. The
+/// address in `auth` is only used by [BuiAppInner::guess_url_with_token].
+pub async fn create_bui_app_inner_without_listener<T, CB>(
+    handle: tokio::runtime::Handle,
+    shutdown_rx: Option<tokio::sync::oneshot::Receiver<()>>,
     auth: &access_control::AccessControl,
     shared_arc: Arc<RwLock<ChangeTracker<T>>>,
     event_name: Option<String>,
     rx_conn: mpsc::Receiver<NewEventStreamConnection>,
     bui_server: BuiService<CB>,
 ) -> Result<(mpsc::Receiver<ConnectionEvent>, BuiAppInner<T, CB>), Error>
+where
+    T: Clone + Serialize + 'static + Send + Sync,
+    CB: serde::de::DeserializeOwned + Clone + Send + 'static,
+{
+    start(
+        handle,
+        shutdown_rx,
+        auth.clone(),
+        shared_arc,
+        event_name,
+        None,
+        rx_conn,
+        bui_server,
+        Vec::new(),
+        5,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn start<T, CB>(
+    handle: tokio::runtime::Handle,
+    mut shutdown_rx: Option<tokio::sync::oneshot::Receiver<()>>,
+    auth: access_control::AccessControl,
+    shared_arc: Arc<RwLock<ChangeTracker<T>>>,
+    event_name: Option<String>,
+    view: Option<ViewFn<T>>,
+    rx_conn: mpsc::Receiver<NewEventStreamConnection>,
+    bui_server: BuiService<CB>,
+    listeners: Vec<(Listener, access_control::AccessControl)>,
+    connection_event_channel_size: usize,
+) -> Result<(mpsc::Receiver<ConnectionEvent>, BuiAppInner<T, CB>), Error>
 where
     T: Clone + Serialize + 'static + Send + Sync,
     CB: serde::de::DeserializeOwned + Clone + Send + 'static,
@@ -134,103 +1257,113 @@ where
 
     let mut rx_conn_valve = valve.wrap(rx_conn);
 
+    let (shutdown_tx, shutdown_watch) = watch::channel(false);
+    let shutdown_tx = Arc::new(shutdown_tx);
+    let (stopped_tx, stopped_rx) = watch::channel(false);
+
     if let Some(shutdown_rx) = shutdown_rx.take() {
+        let shutdown_tx = shutdown_tx.clone();
         handle.spawn(async move {
-            shutdown_rx.await.unwrap();
-            // Cancel the stream. (The receiver will receive end-of-stream.)
-            quit_trigger.cancel();
+            if shutdown_rx.await.is_ok() {
+                shutdown_tx.send_replace(true);
+            }
         });
-    } else {
-        // Allow dropping `quit_trigger` without canceling stream.
-        quit_trigger.disable();
     }
 
-    let bui_server2 = bui_server.clone();
+    let error_handler: Arc<RwLock<Option<ErrorFn>>> = Arc::new(RwLock::new(None));
 
-    let addr = auth.bind_addr();
-    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let listeners = listeners
+        .into_iter()
+        .map(|(listener, auth)| {
+            let local_addr = listener.local_addr()?;
+            Ok((listener, local_addr, bui_server.with_access_control(&auth)))
+        })
+        .collect::<Result<Vec<_>, std::io::Error>>()?;
+    let local_addrs = listeners
+        .iter()
+        .map(|(_, local_addr, server)| (local_addr.clone(), server.access().clone()))
+        .collect();
 
-    let local_addr = listener.local_addr()?;
-    let handle2 = handle.clone();
+    let accept_loops: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, _, server)| {
+            handle.spawn(accept_connections(
+                listener,
+                server,
+                shutdown_watch.clone(),
+                error_handler.clone(),
+            ))
+        })
+        .collect();
 
-    handle.spawn(async move {
-        loop {
-            let (socket, _remote_addr) = listener.accept().await.unwrap();
-            let bui_server = bui_server2.clone();
-
-            // Spawn a task to handle the connection. That way we can multiple connections
-            // concurrently.
-            handle2.spawn(async move {
-                // Hyper has its own `AsyncRead` and `AsyncWrite` traits and doesn't use tokio.
-                // `TokioIo` converts between them.
-                let socket = hyper_util::rt::TokioIo::new(socket);
-                let bui_server = bui_server.clone();
-
-                let hyper_service = hyper::service::service_fn(
-                    move |request: hyper::Request<hyper::body::Incoming>| {
-                        use hyper::service::Service;
-                        // Do we need to call `poll_ready`????
-                        bui_server.call(request)
-                    },
-                );
+    handle.spawn(prune_sessions(bui_server.clone(), shutdown_watch.clone()));
 
-                // `server::conn::auto::Builder` supports both http1 and http2.
-                //
-                // `TokioExecutor` tells hyper to use `tokio::spawn` to spawn tasks.
-                if let Err(err) = hyper_util::server::conn::auto::Builder::new(
-                    hyper_util::rt::TokioExecutor::new(),
-                )
-                // `serve_connection_with_upgrades` is required for websockets. If you don't need
-                // that you can use `serve_connection` instead.
-                .serve_connection_with_upgrades(socket, hyper_service)
-                .await
-                {
-                    eprintln!("failed to serve connection: {err:#}");
-                }
-            });
-        }
-    });
+    // --- handle connections
+    let (new_conn_tx, new_conn_rx) = mpsc::channel(connection_event_channel_size);
+
+    let stream = StateStream::spawn(
+        &handle,
+        shared_arc,
+        event_name,
+        StreamSettings::new(bui_server.config()),
+        view,
+        new_conn_tx.clone(),
+        shutdown_watch.clone(),
+    );
 
     let inner = BuiAppInner {
-        i_shared_arc: shared_arc,
-        i_txers: Arc::new(RwLock::new(HashMap::new())),
+        i_stream: stream.clone(),
+        i_streams: Arc::new(RwLock::new(HashMap::new())),
         i_bui_server: bui_server,
-        auth: auth.clone(),
-        local_addr,
+        auth,
+        local_addrs,
+        handle: handle.clone(),
+        new_conn_tx: new_conn_tx.clone(),
+        shutdown_tx,
+        stopped_rx,
+        error_handler,
     };
 
-    // --- handle connections
-    let (new_conn_tx, new_conn_rx) = mpsc::channel(5); // TODO chan_size
+    // Upon shutdown, stop registering new event streams and close the open
+    // ones. Then wait for all HTTP connections to finish.
+    let streams = inner.i_streams.clone();
+    let stream2 = stream.clone();
+    handle.spawn(async move {
+        shutdown_started(shutdown_watch).await;
+        info!("shutting down");
+        quit_trigger.cancel();
+        stream2.go_away();
+        let path_streams: Vec<_> = streams.read().values().cloned().collect();
+        for path_stream in path_streams {
+            path_stream.go_away();
+        }
+        for accept_loop in accept_loops {
+            if let Err(e) = accept_loop.await {
+                error!("HTTP server task failed: {:?}", e);
+            }
+        }
+        stopped_tx.send_replace(true);
+    });
 
-    let shared_arc = inner.i_shared_arc.clone();
-    let txers2 = inner.i_txers.clone();
-    let new_conn_tx2 = new_conn_tx.clone();
-    let event_name2: Option<String> = event_name.clone();
+    let streams = inner.i_streams.clone();
 
     let handle_connections_fut = async move {
         while let Some(conn_info) = futures::StreamExt::next(&mut rx_conn_valve).await {
-            let chunk_sender = conn_info.chunk_sender;
-            let chunk_sender: EventChunkSender = chunk_sender; // type annotation only
-            let ckey = conn_info.session_key;
-            let connection_key = conn_info.connection_key;
-
-            // send current value on initial connect
-            let hc: hyper::body::Bytes = {
-                let shared = shared_arc.write();
-                create_event_source_msg(&shared.as_ref(), event_name2.as_deref()).into()
-            };
+            // Route the connection to the stream registered for its path.
+            let path_stream = streams.read().get(&conn_info.path).cloned();
+            match path_stream {
+                Some(path_stream) => path_stream.connect(&conn_info),
+                None => stream.connect(&conn_info),
+            }
 
-            let typ = ConnectionEventType::Connect(chunk_sender.clone());
-            let session_key = ckey;
-            let path = conn_info.path.clone();
-            let path2 = conn_info.path.clone();
+            let typ = ConnectionEventType::Connect(conn_info.chunk_sender);
 
-            match new_conn_tx2
+            match new_conn_tx
                 .send(ConnectionEvent {
                     typ,
-                    session_key,
-                    connection_key,
-                    path,
+                    session_key: conn_info.session_key,
+                    connection_key: conn_info.connection_key,
+                    path: conn_info.path,
                 })
                 .await
             {
@@ -242,93 +1375,745 @@ where
                     );
                 }
             };
-
-            match chunk_sender.send(hc).await {
-                Ok(()) => {
-                    let mut txer_guard = txers2.write();
-                    txer_guard.insert(connection_key, (ckey, chunk_sender, path2));
-                }
-                Err(e) => {
-                    error!("failed to send value on initial connect: {:?}", e);
-                }
-            }
         }
     };
 
     handle.spawn(Box::pin(handle_connections_fut));
 
-    // --- push changes
+    Ok((new_conn_rx, inner))
+}
+
+/// Periodically forget sessions which have expired, until shutdown.
+async fn prune_sessions<CB>(bui_server: BuiService<CB>, shutdown: watch::Receiver<bool>)
+where
+    CB: serde::de::DeserializeOwned + Clone + Send + 'static,
+{
+    let mut ticks = futures::StreamExt::take_until(
+        tokio_stream::wrappers::IntervalStream::new(tokio::time::interval(PRUNE_SESSIONS_INTERVAL)),
+        Box::pin(shutdown_started(shutdown)),
+    );
+    while futures::StreamExt::next(&mut ticks).await.is_some() {
+        bui_server.prune_sessions();
+    }
+}
+
+/// Accept connections from `listener` and serve them until shutdown.
+///
+/// Upon shutdown, stop accepting and wait for the open connections to finish,
+/// for at most `Config::shutdown_timeout`.
+async fn accept_connections<CB>(
+    listener: Listener,
+    bui_server: BuiService<CB>,
+    shutdown_watch: watch::Receiver<bool>,
+    error_handler: Arc<RwLock<Option<ErrorFn>>>,
+) where
+    CB: serde::de::DeserializeOwned + Clone + Send + 'static,
+{
+    let shutdown_timeout = bui_server.config().shutdown_timeout;
+    #[cfg(feature = "tls")]
+    let tls_acceptor = bui_server.config().tls.as_ref().map(|tls| tls.acceptor());
 
-    let shared_store2 = inner.i_shared_arc.clone();
-    let txers = inner.i_txers.clone();
-    // Create a Stream to handle updates to our shared store.
-    let change_listener = {
-        let mut rx = {
-            let shared = shared_store2.write();
-            shared.get_changes(10) // capacity of channel is 10 changes
+    let mut connections = tokio::task::JoinSet::new();
+    let mut shutdown = Box::pin(shutdown_started(shutdown_watch.clone()));
+    let mut backoff = None;
+    loop {
+        if let Some(delay) = backoff {
+            let sleep = Box::pin(tokio::time::sleep(delay));
+            match futures::future::select(sleep, shutdown).await {
+                futures::future::Either::Left((_, s)) => shutdown = s,
+                futures::future::Either::Right(_) => break,
+            }
+        }
+        let accepted = match futures::future::select(Box::pin(listener.accept()), shutdown).await {
+            futures::future::Either::Left((accepted, s)) => {
+                shutdown = s;
+                accepted
+            }
+            futures::future::Either::Right(_) => break,
         };
-        async move {
-            while let Some((_old, new_value)) = futures::StreamExt::next(&mut rx).await {
-                // We need to hold the loc on txers only briefly, so we do this.
-                let sources_drain = {
-                    let mut sources = txers.write();
-                    sources.drain().collect::<Vec<_>>()
-                };
 
-                let mut restore = vec![];
+        // Forget about connections which have finished.
+        while connections.try_join_next().is_some() {}
 
-                let event_source_msg = create_event_source_msg(&new_value, event_name.as_deref());
+        let (socket, remote_addr) = match accepted {
+            Ok(accepted) => {
+                backoff = None;
+                accepted
+            }
+            Err(e) => {
+                // Errors concerning a single connection do not prevent
+                // accepting others. For other errors, such as running out
+                // of file descriptors, wait before trying again.
+                backoff = if is_connection_error(&e) {
+                    None
+                } else {
+                    Some(match backoff {
+                        None => std::time::Duration::from_millis(10),
+                        Some(delay) => std::cmp::min(delay * 2, MAX_ACCEPT_BACKOFF),
+                    })
+                };
+                error!("failed to accept connection: {}", e);
+                report_error(&error_handler, Error::Accept(e));
+                continue;
+            }
+        };
+        let bui_server = bui_server.clone();
+        let shutdown_watch = shutdown_watch.clone();
+        let error_handler = error_handler.clone();
+        #[cfg(feature = "tls")]
+        let tls_acceptor = tls_acceptor.clone();
 
-                for (connection_key, (session_key, tx, path)) in sources_drain {
-                    let chunk = event_source_msg.clone().into();
-                    match tx.send(chunk).await {
-                        Ok(()) => {
-                            restore.push((connection_key, (session_key, tx, path)));
-                        }
-                        Err(e) => {
-                            info!(
-                                "Failed to send data to event stream, client \
-                                    probably disconnected. {:?}",
-                                e
-                            );
-                            let nct = new_conn_tx.clone();
-                            let typ = ConnectionEventType::Disconnect;
-                            let ce = ConnectionEvent {
-                                typ,
-                                session_key,
-                                connection_key,
-                                path,
-                            };
-                            match nct.send(ce).await {
-                                Ok(()) => {}
-                                Err(e) => {
-                                    info!(
-                                        "Failed to send ConnectionEvent, \
-                                    probably no listener. {:?}",
-                                        e
-                                    );
-                                }
-                            };
+        // Spawn a task to handle the connection. That way we can multiple connections
+        // concurrently.
+        connections.spawn(async move {
+            #[cfg(feature = "tls")]
+            let result = match tls_acceptor {
+                Some(tls_acceptor) => {
+                    // Clients which never complete the handshake would
+                    // otherwise hold the connection open.
+                    let handshake = tls_acceptor.accept(socket);
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(socket)) => {
+                            serve_connection(
+                                socket,
+                                remote_addr.clone(),
+                                bui_server,
+                                shutdown_watch,
+                            )
+                            .await
                         }
-                    };
+                        Ok(Err(e)) => Err(e.into()),
+                        Err(_) => Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "TLS handshake timed out",
+                        )
+                        .into()),
+                    }
                 }
-                for (connection_key, element) in restore.into_iter() {
-                    let mut sources = txers.write();
-                    sources.insert(connection_key, element);
+                None => {
+                    serve_connection(socket, remote_addr.clone(), bui_server, shutdown_watch).await
                 }
+            };
+            #[cfg(not(feature = "tls"))]
+            let result =
+                serve_connection(socket, remote_addr.clone(), bui_server, shutdown_watch).await;
+
+            if let Err(source) = result {
+                info!(
+                    "failed to serve connection from {}: {:#}",
+                    remote_addr, source
+                );
+                report_error(
+                    &error_handler,
+                    Error::ServeConnection {
+                        remote_addr,
+                        source,
+                    },
+                );
             }
+        });
+    }
+
+    // Stop accepting connections.
+    drop(listener);
+
+    let drain = async { while connections.join_next().await.is_some() {} };
+    if tokio::time::timeout(shutdown_timeout, drain).await.is_err() {
+        warn!(
+            "{} connections still open after shutdown timeout, aborting",
+            connections.len()
+        );
+        connections.abort_all();
+        while connections.join_next().await.is_some() {}
+    }
+}
+
+/// Serve HTTP on a connection until it is closed or shutdown completes, and
+/// then the WebSockets upgraded from it until they are closed.
+async fn serve_connection<I, CB>(
+    io: I,
+    remote_addr: Address,
+    bui_server: BuiService<CB>,
+    shutdown_watch: watch::Receiver<bool>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    CB: serde::de::DeserializeOwned + Clone + Send + 'static,
+{
+    // Hyper has its own `AsyncRead` and `AsyncWrite` traits and doesn't use tokio.
+    // `TokioIo` converts between them.
+    let io = hyper_util::rt::TokioIo::new(io);
+
+    let websockets = WebSocketTasks::default();
+    let websockets2 = websockets.clone();
+    let hyper_service =
+        hyper::service::service_fn(move |mut request: hyper::Request<hyper::body::Incoming>| {
+            use hyper::service::Service;
+            // Make the client address known to the session registry.
+            request.extensions_mut().insert(remote_addr.clone());
+            request.extensions_mut().insert(websockets2.clone());
+            // Do we need to call `poll_ready`????
+            bui_server.call(request)
+        });
+
+    // `server::conn::auto::Builder` supports both http1 and http2. Without
+    // TLS, http2 is used by clients sending the http2 preface right away
+    // ("prior knowledge").
+    //
+    // `TokioExecutor` tells hyper to use `tokio::spawn` to spawn tasks.
+    let builder =
+        hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+    // `serve_connection_with_upgrades` is required for websockets. If you don't need
+    // that you can use `serve_connection` instead.
+    let mut conn = std::pin::pin!(builder.serve_connection_with_upgrades(io, hyper_service));
+
+    // Upon shutdown, finish the requests in progress and then close.
+    let shutdown = Box::pin(shutdown_started(shutdown_watch));
+    let result = match futures::future::select(conn.as_mut(), shutdown).await {
+        futures::future::Either::Left((result, _)) => result,
+        futures::future::Either::Right(_) => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
         }
     };
-    handle.spawn(Box::pin(change_listener));
+    // Upon shutdown, the WebSockets are closed with their event streams.
+    websockets.join().await;
+    result
+}
 
-    Ok((new_conn_rx, inner))
+/// Whether an error accepting a connection concerns only that connection.
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// Pass an error to the error handler, if one is set.
+fn report_error(error_handler: &RwLock<Option<ErrorFn>>, e: Error) {
+    let error_handler = error_handler.read().clone();
+    if let Some(error_handler) = error_handler {
+        error_handler(e);
+    }
+}
+
+/// Resolves once shutdown has started.
+async fn shutdown_started(mut shutdown: watch::Receiver<bool>) {
+    // An error means the sender was dropped, in which case shutdown never
+    // starts.
+    if shutdown.wait_for(|started| *started).await.is_err() {
+        futures::future::pending::<()>().await;
+    }
+}
+
+/// Inform the listener that a connection was closed.
+async fn send_disconnect(
+    new_conn_tx: &mpsc::Sender<ConnectionEvent>,
+    session_key: SessionKey,
+    connection_key: ConnectionKey,
+    path: String,
+) {
+    let typ = ConnectionEventType::Disconnect;
+    let ce = ConnectionEvent {
+        typ,
+        session_key,
+        connection_key,
+        path,
+    };
+    match new_conn_tx.send(ce).await {
+        Ok(()) => {}
+        Err(e) => {
+            info!(
+                "Failed to send ConnectionEvent, \
+            probably no listener. {:?}",
+                e
+            );
+        }
+    };
+}
+
+/// Compute the patch which transforms `prev` into `new`.
+///
+/// Returns `None` if the values are equal or `update_mode` does not use
+/// patches.
+fn compute_patch(
+    update_mode: UpdateMode,
+    prev: &serde_json::Value,
+    new: &serde_json::Value,
+) -> Option<serde_json::Value> {
+    match update_mode {
+        UpdateMode::FullState => None,
+        UpdateMode::JsonPatch => {
+            let patch = json_patch::diff(prev, new);
+            if patch.0.is_empty() {
+                None
+            } else {
+                Some(serde_json::to_value(&patch).expect("encode"))
+            }
+        }
+        UpdateMode::MergePatch => merge_patch_diff(prev, new),
+    }
+}
+
+/// Compute a JSON Merge Patch (RFC 7386) which transforms `old` into `new`.
+///
+/// Returns `None` if the values are equal.
+fn merge_patch_diff(old: &serde_json::Value, new: &serde_json::Value) -> Option<serde_json::Value> {
+    use serde_json::{Map, Value};
+
+    if old == new {
+        return None;
+    }
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut patch = Map::new();
+            for key in old.keys() {
+                if !new.contains_key(key) {
+                    patch.insert(key.clone(), Value::Null);
+                }
+            }
+            for (key, new_value) in new.iter() {
+                match old.get(key) {
+                    Some(old_value) if new_value.is_object() => {
+                        if let Some(value) = merge_patch_diff(old_value, new_value) {
+                            patch.insert(key.clone(), value);
+                        }
+                    }
+                    Some(old_value) if old_value == new_value => {}
+                    _ => {
+                        patch.insert(key.clone(), new_value.clone());
+                    }
+                }
+            }
+            Some(Value::Object(patch))
+        }
+        _ => Some(new.clone()),
+    }
 }
 
-fn create_event_source_msg<T: serde::Serialize>(value: &T, event_name: Option<&str>) -> String {
+fn create_event_source_msg<T: serde::Serialize>(
+    value: &T,
+    event_name: Option<&str>,
+    id: Option<&str>,
+) -> String {
     let buf = serde_json::to_string(&value).expect("encode");
+    let mut msg = String::new();
+    if let Some(id) = id {
+        msg.push_str(&format!("id: {}\n", id));
+    }
     if let Some(event_name) = event_name {
-        format!("event: {}\ndata: {}\n\n", event_name, buf)
-    } else {
-        format!("data: {}\n\n", buf)
+        msg.push_str(&format!("event: {}\n", event_name));
+    }
+    msg.push_str(&format!("data: {}\n\n", buf));
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lowlevel::tests::{
+        event_stream_request, next_chunk, runtime, test_config, NoopHandler,
+    };
+    use serde_json::json;
+
+    /// Serve `state` at `/events` to local clients without authentication.
+    async fn test_app<T>(config: Config, state: T) -> crate::BuiApp<T, u8>
+    where
+        T: Clone + Serialize + 'static + Send + Sync,
+    {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        crate::BuiApp::builder(config, Arc::new(RwLock::new(ChangeTracker::new(state))))
+            .auth(access_control::AccessControl::Insecure(addr))
+            .callback_handler(NoopHandler)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    /// Open an event stream at `path` and return its body.
+    async fn open_event_stream<CB>(
+        service: &BuiService<CB>,
+        path: &str,
+    ) -> http_body_util::combinators::BoxBody<bytes::Bytes, hyper::Error>
+    where
+        CB: 'static + serde::de::DeserializeOwned + Clone + Send,
+    {
+        let mut service = service.clone();
+        let resp = tower_service::Service::call(&mut service, event_stream_request(path))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        resp.into_body()
+    }
+
+    /// The next chunk of `body` as text.
+    async fn next_text(
+        body: &mut http_body_util::combinators::BoxBody<bytes::Bytes, hyper::Error>,
+    ) -> String {
+        let chunk = next_chunk(body).await.expect("chunk");
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    /// Serve `0` at `/events` to sessions started with the token `token`.
+    async fn token_app() -> crate::BuiApp<u8, u8> {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let auth = generate_auth_with_token(addr, b"secret".to_vec(), "token".into()).unwrap();
+        crate::BuiApp::builder(test_config(), Arc::new(RwLock::new(ChangeTracker::new(0))))
+            .auth(auth)
+            .callback_handler(NoopHandler)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    /// Open an event stream at `path` with the session cookie `cookie`.
+    async fn event_stream_with_cookie<CB>(
+        service: &BuiService<CB>,
+        path: &str,
+        cookie: Option<&str>,
+    ) -> http::Response<http_body_util::combinators::BoxBody<bytes::Bytes, hyper::Error>>
+    where
+        CB: 'static + serde::de::DeserializeOwned + Clone + Send,
+    {
+        let mut req = event_stream_request(path);
+        if let Some(cookie) = cookie {
+            req.headers_mut()
+                .insert(hyper::header::COOKIE, cookie.parse().unwrap());
+        }
+        let mut service = service.clone();
+        tower_service::Service::call(&mut service, req)
+            .await
+            .unwrap()
+    }
+
+    /// The session cookie set by `resp`, as sent back by the browser.
+    fn session_cookie<B>(resp: &http::Response<B>) -> Option<String> {
+        let set_cookie = resp.headers().get(hyper::header::SET_COOKIE)?;
+        let cookie = set_cookie.to_str().unwrap().split(';').next().unwrap();
+        Some(cookie.to_string())
+    }
+
+    #[test]
+    fn rotate_jwt_secret_with_grace() {
+        runtime().block_on(async {
+            let app = token_app().await;
+            let service = app.inner.bui_service();
+            let resp = event_stream_with_cookie(service, "/events?token=token", None).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            let old_cookie = session_cookie(&resp).unwrap();
+            let mut old_body = resp.into_body();
+            next_text(&mut old_body).await;
+
+            // During the grace period, the old cookie is accepted and
+            // replaced by one signed with the new secret.
+            app.inner
+                .rotate_jwt_secret(b"secret2", Some(std::time::Duration::from_millis(200)));
+            let resp = event_stream_with_cookie(service, "/events", Some(&old_cookie)).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            let new_cookie = session_cookie(&resp).unwrap();
+            assert_ne!(new_cookie, old_cookie);
+            let resp = event_stream_with_cookie(service, "/events", Some(&new_cookie)).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            assert_eq!(session_cookie(&resp), None);
+
+            // Afterwards, only the new cookie is accepted. Sessions continue.
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            let resp = event_stream_with_cookie(service, "/events", Some(&old_cookie)).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+            let resp = event_stream_with_cookie(service, "/events", Some(&new_cookie)).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            app.inner.shared_arc().write().modify(|x| *x = 1);
+            assert!(next_text(&mut old_body).await.contains("data: 1\n"));
+
+            app.inner.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn rotate_jwt_secret_without_grace() {
+        runtime().block_on(async {
+            let app = token_app().await;
+            let service = app.inner.bui_service();
+            let resp = event_stream_with_cookie(service, "/events?token=token", None).await;
+            let cookie = session_cookie(&resp).unwrap();
+            let mut body = resp.into_body();
+            next_text(&mut body).await;
+            assert_eq!(app.inner.sessions().len(), 1);
+
+            // Existing sessions end and their streams are closed.
+            app.inner.rotate_jwt_secret(b"secret2", None);
+            assert!(next_chunk(&mut body).await.is_none());
+            let resp = event_stream_with_cookie(service, "/events", Some(&cookie)).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+            // New sessions can be started with the token.
+            let resp = event_stream_with_cookie(service, "/events?token=token", None).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+
+            app.inner.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn rotate_token() {
+        runtime().block_on(async {
+            let app = token_app().await;
+            let service = app.inner.bui_service();
+            let resp = event_stream_with_cookie(service, "/events?token=token", None).await;
+            let cookie = session_cookie(&resp).unwrap();
+
+            app.inner.rotate_token("token2").unwrap();
+            assert_eq!(
+                app.inner.token(),
+                AccessToken::PreSharedToken("token2".into())
+            );
+            let resp = event_stream_with_cookie(service, "/events?token=token", None).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+            let resp = event_stream_with_cookie(service, "/events?token=token2", None).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            // Sessions already started continue.
+            let resp = event_stream_with_cookie(service, "/events", Some(&cookie)).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+
+            app.inner.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn state_streams_by_path() {
+        runtime().block_on(async {
+            let app = test_app(test_config(), json!("main")).await;
+            let extra = Arc::new(RwLock::new(ChangeTracker::new(json!("extra"))));
+            app.inner
+                .add_state_stream("/events/extra", extra.clone(), Some("extra".into()))
+                .unwrap();
+
+            // A path is served by one stream only.
+            let other = Arc::new(RwLock::new(ChangeTracker::new(json!("other"))));
+            let e = app
+                .inner
+                .add_state_stream("/events/extra", other.clone(), None)
+                .unwrap_err();
+            assert!(matches!(e, Error::DuplicateEventsPath(ref p) if p == "/events/extra"));
+            let e = app
+                .inner
+                .add_state_stream("/other", other, None)
+                .unwrap_err();
+            assert!(matches!(e, Error::NotAnEventsPath(_)));
+
+            let service = app.inner.bui_service();
+            let mut main_body = open_event_stream(service, "/events").await;
+            assert!(next_text(&mut main_body).await.contains("data: \"main\"\n"));
+            let mut extra_body = open_event_stream(service, "/events/extra").await;
+            let text = next_text(&mut extra_body).await;
+            assert!(text.contains("event: extra\ndata: \"extra\"\n"), "{}", text);
+            // Other paths are served the main stream.
+            let mut unknown_body = open_event_stream(service, "/events/unknown").await;
+            assert!(next_text(&mut unknown_body)
+                .await
+                .contains("data: \"main\"\n"));
+
+            // Changes are only sent to the connections of their stream.
+            extra.write().modify(|x| *x = json!("extra2"));
+            app.inner
+                .shared_arc()
+                .write()
+                .modify(|x| *x = json!("main2"));
+            assert!(next_text(&mut extra_body)
+                .await
+                .contains("data: \"extra2\"\n"));
+            assert!(next_text(&mut main_body)
+                .await
+                .contains("data: \"main2\"\n"));
+            assert!(next_text(&mut unknown_body)
+                .await
+                .contains("data: \"main2\"\n"));
+
+            app.inner.shutdown().await;
+            // Clients of every stream are told that the server goes away.
+            let going_away = format!("event: {}\n", bui_backend_types::GOING_AWAY_EVENT);
+            for body in [&mut main_body, &mut extra_body, &mut unknown_body] {
+                assert!(next_text(body).await.contains(&going_away));
+                assert!(next_chunk(body).await.is_none());
+            }
+        });
+    }
+
+    fn queued(outbox: &Outbox) -> Vec<hyper::body::Bytes> {
+        outbox.state.lock().queue.iter().cloned().collect()
+    }
+
+    #[test]
+    fn outbox_overflow_policies() {
+        let chunk = |s: &'static str| hyper::body::Bytes::from(s);
+
+        let outbox = Outbox::default();
+        for c in ["a", "b", "c"] {
+            assert!(outbox.push(chunk(c), 2, OverflowPolicy::DropOldest, || chunk("s")));
+        }
+        assert_eq!(queued(&outbox), vec![chunk("b"), chunk("c")]);
+
+        let outbox = Outbox::default();
+        for c in ["a", "b", "c"] {
+            assert!(outbox.push(chunk(c), 2, OverflowPolicy::CoalesceToLatest, || chunk("s")));
+        }
+        assert_eq!(queued(&outbox), vec![chunk("s")]);
+
+        let outbox = Outbox::default();
+        assert!(outbox.push(chunk("a"), 1, OverflowPolicy::Disconnect, || chunk("s")));
+        assert!(!outbox.push(chunk("b"), 1, OverflowPolicy::Disconnect, || chunk("s")));
+        assert!(!outbox.heartbeat(chunk(":")));
+    }
+
+    #[test]
+    fn outbox_heartbeat_only_when_idle() {
+        let outbox = Outbox::default();
+        assert!(outbox.heartbeat(":".into()));
+        assert!(outbox.heartbeat(":".into()));
+        assert_eq!(queued(&outbox).len(), 1);
+        outbox.close();
+        assert!(!outbox.heartbeat(":".into()));
+    }
+
+    /// Create a history of the events `1` to `n`, keeping `size` of them.
+    fn history_with_events(n: u64, size: usize) -> EventHistory {
+        history_of(EventHistory::new(json!(null), false), n, size)
+    }
+
+    fn history_of(mut history: EventHistory, n: u64, size: usize) -> EventHistory {
+        for _ in 0..n {
+            let id = history.next_event_id();
+            history.push(format!("{};", id).into(), size);
+        }
+        history
+    }
+
+    fn id(history: &EventHistory, n: u64) -> String {
+        format!("{}-{}", history.epoch, n)
+    }
+
+    #[test]
+    fn events_since_replays_missed_events() {
+        let history = history_with_events(3, 10);
+        let expected = format!("{};{};", id(&history, 2), id(&history, 3));
+        assert_eq!(
+            history.events_since(&id(&history, 1)),
+            Some(expected.into())
+        );
+        // A client which missed nothing is sent nothing.
+        assert_eq!(
+            history.events_since(&id(&history, 3)),
+            Some(hyper::body::Bytes::new())
+        );
+    }
+
+    #[test]
+    fn events_since_after_wraparound() {
+        // Only the events 3 to 5 are kept.
+        let history = history_with_events(5, 3);
+        let expected = format!(
+            "{};{};{};",
+            id(&history, 3),
+            id(&history, 4),
+            id(&history, 5)
+        );
+        assert_eq!(
+            history.events_since(&id(&history, 2)),
+            Some(expected.into())
+        );
+        // Event 2 was dropped, so the gap cannot be filled.
+        assert_eq!(history.events_since(&id(&history, 1)), None);
+        assert_eq!(history.events_since(&id(&history, 0)), None);
+    }
+
+    #[test]
+    fn events_since_rejects_unknown_ids() {
+        let history = history_with_events(3, 10);
+        // From the future.
+        assert_eq!(history.events_since(&id(&history, 4)), None);
+        // From a previous run of the server.
+        let other_epoch = format!("{}0-1", history.epoch);
+        assert_eq!(history.events_since(&other_epoch), None);
+        // Malformed.
+        assert_eq!(history.events_since(&history.epoch), None);
+        assert_eq!(history.events_since(&format!("{}-x", history.epoch)), None);
+        assert_eq!(history.events_since(""), None);
+    }
+
+    #[test]
+    fn events_since_without_buffer() {
+        let history = history_with_events(2, 0);
+        assert_eq!(
+            history.events_since(&id(&history, 2)),
+            Some(hyper::body::Bytes::new())
+        );
+        assert_eq!(history.events_since(&id(&history, 1)), None);
+    }
+
+    #[test]
+    fn events_since_full_state() {
+        let history = history_of(EventHistory::new(json!(null), true), 3, 10);
+        assert_eq!(history.buffer.len(), 1);
+        // Only the latest state is replayed.
+        let expected = format!("{};", id(&history, 3));
+        assert_eq!(
+            history.events_since(&id(&history, 1)),
+            Some(expected.clone().into())
+        );
+        assert_eq!(
+            history.events_since(&id(&history, 0)),
+            Some(expected.into())
+        );
+        assert_eq!(
+            history.events_since(&id(&history, 3)),
+            Some(hyper::body::Bytes::new())
+        );
+        assert_eq!(history.events_since(&id(&history, 4)), None);
+
+        let history = history_of(EventHistory::new(json!(null), true), 3, 0);
+        assert_eq!(history.events_since(&id(&history, 1)), None);
+    }
+
+    #[test]
+    fn merge_patch_diff_rfc7386_examples() {
+        // The examples of RFC 7386, appendix A, as (original, result). The
+        // computed patch must transform the original into the result.
+        let examples = [
+            (json!({"a": "b"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"a": "b", "b": "c"})),
+            (json!({"a": "b"}), json!({})),
+            (json!({"a": "b", "b": "c"}), json!({"b": "c"})),
+            (json!({"a": ["b"]}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]})),
+            (json!({"a": {"b": "c"}}), json!({"a": {"b": "d"}})),
+            (json!({"a": [{"b": "c"}]}), json!({"a": [1]})),
+            (json!(["a", "b"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"])),
+            (json!({"a": "foo"}), json!(null)),
+            (json!({"a": "foo"}), json!("bar")),
+            (json!({"e": null}), json!({"e": null, "a": 1})),
+            (json!([1, 2]), json!({"a": "b"})),
+            (json!({}), json!({"a": {"bb": {}}})),
+        ];
+        for (original, result) in examples.iter() {
+            let patch = merge_patch_diff(original, result).unwrap();
+            let mut patched = original.clone();
+            json_patch::merge(&mut patched, &patch);
+            assert_eq!(&patched, result, "patch {} from {}", patch, original);
+        }
+    }
+
+    #[test]
+    fn merge_patch_diff_is_minimal() {
+        let old = json!({"a": {"b": 1, "c": 2}, "d": [1, 2], "e": "x"});
+        let new = json!({"a": {"b": 1, "c": 3}, "d": [1, 2]});
+        assert_eq!(
+            merge_patch_diff(&old, &new),
+            Some(json!({"a": {"c": 3}, "e": null}))
+        );
+        assert_eq!(merge_patch_diff(&old, &old), None);
     }
 }
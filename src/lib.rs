@@ -37,13 +37,49 @@
 //!    [`async-change-tracker`](https://crates.io/crates/async-change-tracker)
 //!    type to ensure that server state changes are reactively sent to all
 //!    connected frontends.
-//!  - To keep things simple, server state is shared with all connected clients.
+//!  - By default, server state is shared with all connected clients. Optionally,
+//!    each connection sees its own view of the state, computed by a function
+//!    set with `BuiAppBuilder::view()`.
+//!  - Several independent states may be served, each on its own event stream
+//!    path, with `BuiAppInner::add_state_stream()`.
+//!  - Optionally, changes to the server state are sent as [JSON
+//!    Patch](https://tools.ietf.org/html/rfc6902) or [JSON Merge
+//!    Patch](https://tools.ietf.org/html/rfc7386) documents rather than the full
+//!    state.
 //!  - Session keys (per browser) and connection keys (per tab) are maintained
 //!    and allow taking control of communication using pre-established event
 //!    stream. (This is an "escape hatch" to break out of the bui-backend
 //!    abstractions as required by some use cases.)
+//!  - Sessions are registered on the server, where they can be listed and
+//!    revoked. Browsers can log out.
+//!  - The pre-shared token and the secret signing session cookies can be
+//!    rotated while the server runs.
+//!  - Access is restricted with a pre-shared token or with user names and
+//!    passwords (HTTP Basic, a login form, or an htpasswd file). The identity
+//!    of the user is passed to callbacks. Viewers may watch, while only
+//!    controllers may send callbacks.
+//!  - Browsers may alternatively open a WebSocket on the event stream path. This
+//!    carries the same events downstream (as JSON encoded
+//!    `bui_backend_types::WebSocketEvent` messages) and accepts callback
+//!    payloads upstream. WebSockets are only accepted from pages served by the
+//!    same host or from the origins in `Config::allowed_origins`.
 //!  - Written in asynchronous rust using async/await.
 //!  - Uses [Serde JSON](https://crates.io/crates/serde_json).
+//!  - Callbacks and WebSocket messages may alternatively be encoded as
+//!    MessagePack or CBOR (with `msgpack` and `cbor` features, see
+//!    `bui_backend_types::Encoding`).
+//!  - Optionally serves over HTTPS using [rustls](https://crates.io/crates/rustls)
+//!    (with `tls` feature).
+//!  - Supports HTTP/2, so that browsers can open many event streams (e.g. in
+//!    many tabs) over one connection.
+//!  - Serves on TCP, on Unix domain sockets, or on listeners passed by systemd
+//!    socket activation.
+//!  - Several listeners may be served at once, each with its own access control
+//!    (e.g. no token on the loopback interface, a token elsewhere).
+//!  - The BUI may be mounted in another server, such as an
+//!    [axum](https://crates.io/crates/axum) application, as a tower `Service`.
+//!  - Optionally served below a base path, so that several BUIs can share one
+//!    host behind a reverse proxy.
 //!  - Compile-time choice between bundling served files into executable (with
 //!    `bundle_files` feature) or reading files from disk (`serve_files`).
 //!
@@ -56,15 +92,10 @@
 //! #### Potential improvements
 //!
 //!  - Add example with user login.
-//!  - Send minimal differences when state changes, likely by improving
-//!    [`async-change-tracker`](https://crates.io/crates/async-change-tracker).
-//!  - Implement more sophisticated state-sharing allowing partial views and
-//!    minimal updates.
 //!  - Use
 //!    [`ReadableStream`](https://developer.mozilla.org/en-US/docs/Web/API/ReadableStream)
 //!    instead of [`Server Sent
 //!    Events`](https://caniuse.com/#search=EventSource).
-//!  - Add a websocket transport option as an alternative to Server Sent Events.
 //!  - Your idea here.
 //!
 //! #### Security warning
@@ -200,9 +231,18 @@ mod errors;
 pub use errors::Error;
 
 pub mod access_control;
+pub mod auth;
 pub use access_control::AccessControl;
 
+pub use builder::{BuiApp, BuiAppBuilder};
+pub use listener::{Address, Listener};
+
+pub mod builder;
 pub mod highlevel;
+pub mod listener;
 pub mod lowlevel;
+pub mod session;
+pub mod tls;
 
-pub use lowlevel::CallbackHandler;
+pub use lowlevel::{CallbackError, CallbackHandler, CallbackHandlerWithResponse};
+pub use session::SessionInfo;
@@ -0,0 +1,145 @@
+//! Sources of incoming connections
+
+use std::net::SocketAddr;
+
+/// Listens for incoming connections to the server.
+///
+/// Besides binding a TCP address, connections can be accepted on a Unix
+/// domain socket or on a listener which was bound elsewhere, such as one
+/// passed by systemd socket activation.
+#[derive(Debug)]
+pub enum Listener {
+    /// A TCP listener.
+    Tcp(tokio::net::TcpListener),
+    /// A Unix domain socket listener.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    Unix(tokio::net::UnixListener),
+}
+
+/// The address of a listener or of a connected client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    /// An IP address and port.
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket, if it has one.
+    ///
+    /// Clients connecting to a Unix domain socket usually have no path.
+    Unix(Option<std::path::PathBuf>),
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Tcp(addr) => addr.fmt(f),
+            Address::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            Address::Unix(None) => write!(f, "unix:(unnamed)"),
+        }
+    }
+}
+
+/// A connection accepted by a [Listener].
+pub(crate) trait Connection: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send {}
+
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send> Connection for T {}
+
+impl Listener {
+    /// Bind a TCP listener to `addr`.
+    pub async fn bind_tcp(addr: SocketAddr) -> std::io::Result<Self> {
+        Ok(Listener::Tcp(tokio::net::TcpListener::bind(addr).await?))
+    }
+
+    /// Bind a Unix domain socket listener to `path`.
+    ///
+    /// A stale socket file at `path`, for example left by a previous run, is
+    /// removed first. Access to the socket can be restricted by setting the
+    /// permissions of the socket file or its directory.
+    ///
+    /// This must be called within a tokio runtime.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn bind_unix<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let path = path.as_ref();
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(Listener::Unix(tokio::net::UnixListener::bind(path)?))
+    }
+
+    /// Use a TCP listener which was bound elsewhere.
+    ///
+    /// This must be called within a tokio runtime.
+    pub fn from_std_tcp(listener: std::net::TcpListener) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Tcp(tokio::net::TcpListener::from_std(listener)?))
+    }
+
+    /// Use a Unix domain socket listener which was bound elsewhere.
+    ///
+    /// This must be called within a tokio runtime.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub fn from_std_unix(listener: std::os::unix::net::UnixListener) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(tokio::net::UnixListener::from_std(
+            listener,
+        )?))
+    }
+
+    /// Take the first listener passed by systemd socket activation.
+    ///
+    /// Returns `Ok(None)` if no listener was passed (i.e. the `LISTEN_FDS`
+    /// and `LISTEN_PID` environment variables do not specify one for this
+    /// process).
+    ///
+    /// This must be called within a tokio runtime.
+    pub fn from_systemd() -> std::io::Result<Option<Self>> {
+        let mut listenfd = listenfd::ListenFd::from_env();
+        let tcp_result = listenfd.take_tcp_listener(0);
+        #[cfg(unix)]
+        {
+            if tcp_result.is_err() {
+                if let Some(listener) = listenfd.take_unix_listener(0)? {
+                    return Ok(Some(Self::from_std_unix(listener)?));
+                }
+            }
+        }
+        match tcp_result? {
+            Some(listener) => Ok(Some(Self::from_std_tcp(listener)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The address on which connections are accepted.
+    pub fn local_addr(&self) -> std::io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Address::Unix(
+                listener.local_addr()?.as_pathname().map(Into::into),
+            )),
+        }
+    }
+
+    /// Accept a connection.
+    pub(crate) async fn accept(
+        &self,
+    ) -> std::io::Result<(std::pin::Pin<Box<dyn Connection>>, Address)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, remote_addr) = listener.accept().await?;
+                Ok((Box::pin(socket), Address::Tcp(remote_addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (socket, remote_addr) = listener.accept().await?;
+                let remote_addr = Address::Unix(remote_addr.as_pathname().map(Into::into));
+                Ok((Box::pin(socket), remote_addr))
+            }
+        }
+    }
+}
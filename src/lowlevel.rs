@@ -14,14 +14,35 @@ use hyper::{
 
 type MyBody = http_body_util::combinators::BoxBody<bytes::Bytes, hyper::Error>;
 
+/// The body of requests passed to a [RawReqHandler].
+///
+/// Requests are converted to this type so they can come from hyper directly
+/// or from another framework via the `tower_service::Service` implementation
+/// of [BuiService].
+pub type RequestBody = http_body_util::combinators::UnsyncBoxBody<bytes::Bytes, BoxError>;
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
 use tokio::sync::mpsc;
 use tokio_stream::StreamExt;
 
 use parking_lot::Mutex;
 use std::sync::Arc;
 
-use crate::access_control;
-use bui_backend_types::{AccessToken, CallbackDataAndSession, ConnectionKey, SessionKey};
+use crate::access_control::{self, SharedAccess};
+use crate::auth::{
+    authenticate_blocking, AuthOutcome, Authenticator, Credentials, LoginThrottle, Throttled,
+};
+use crate::session::{SessionInfo, SessionRegistry};
+use crate::Address;
+use bui_backend_types::{
+    BinaryWebSocketEvent, CallbackDataAndSession, ConnectionKey, Encoding, EncodingError, Identity,
+    Role, SessionKey, WebSocketEvent, CALLBACK_ERROR_EVENT, CALLBACK_REPLY_EVENT,
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role as WsRole, Message},
+    WebSocketStream,
+};
 
 #[cfg(feature = "serve_files")]
 use std::io::Read;
@@ -31,19 +52,46 @@ use serde::{Deserialize, Serialize};
 // ---------------------------
 const JSON_TYPE: &str = "application/json";
 const JSON_NULL: &[u8] = b"{}";
+const SESSION_EXPIRED_MSG: &str = "session expired, re-enter token";
+const SESSION_REVOKED_MSG: &str = "session ended, re-enter token";
+
+/// The identity and role with which a session was authenticated.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct SessionAuth {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    identity: Option<Identity>,
+    /// Cookies issued before roles were introduced have no role.
+    #[serde(default)]
+    role: Role,
+}
+
+impl SessionAuth {
+    /// The data passed to the callback handler for `payload`.
+    fn callback_data<CB>(self, payload: CB, session_key: SessionKey) -> CallbackDataAndSession<CB> {
+        let mut data = CallbackDataAndSession::new(payload, session_key);
+        data.identity = self.identity;
+        data.role = self.role;
+        data
+    }
+}
 
 /// The claims validated using JSON Web Tokens.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct JwtClaims {
     key: SessionKey,
+    #[serde(flatten)]
+    auth: SessionAuth,
     exp: usize, // Required (validate_exp defaults to true in validation). Expiration time (as UTC timestamp)
 }
 
 /// Configuration settings for `BuiService`.
 ///
 /// Defaults can be loaded using the function `get_default_config()`
-/// generated by the `bui_backend_codegen` crate.
+/// generated by the `bui_backend_codegen` crate, or with [Config::new].
+/// Fields may be added in future versions, so settings are changed by
+/// assigning to the fields of such a default configuration.
 #[derive(Clone)]
+#[non_exhaustive]
 pub struct Config {
     /// Location of the files to be served.
     pub serve_filepath: &'static std::path::Path,
@@ -55,6 +103,155 @@ pub struct Config {
     pub channel_size: usize,
     /// The name of the cookie stored in the clients browser.
     pub cookie_name: String,
+    /// The path under which the BUI is served, such as `/devices/cam1`.
+    ///
+    /// Static files, the event streams and the callback route are all served
+    /// below this path and session cookies are scoped to it, so several BUIs
+    /// can be served on one host behind a reverse proxy. Requests for other
+    /// paths are answered with status 404. Use `/` to serve at the root.
+    pub base_path: String,
+    /// How changes to the shared state are sent to connected clients.
+    pub update_mode: UpdateMode,
+    /// The number of recently sent events kept to resend to reconnecting
+    /// clients.
+    ///
+    /// With [UpdateMode::FullState], only the most recent event is needed and
+    /// kept, unless this is zero.
+    pub replay_buffer_size: usize,
+    /// If set, send a comment line on each event stream at this interval.
+    ///
+    /// This keeps proxies and load balancers from closing idle connections.
+    pub heartbeat_interval: Option<std::time::Duration>,
+    /// If set, tell the browser to wait this long before reconnecting.
+    ///
+    /// This is sent in the `retry` field at the start of each event stream.
+    pub retry_interval: Option<std::time::Duration>,
+    /// What to do when a client does not keep up with the sent events.
+    pub overflow_policy: OverflowPolicy,
+    /// How long to wait for requests in progress to complete upon shutdown.
+    pub shutdown_timeout: std::time::Duration,
+    /// If set, serve over HTTPS.
+    pub tls: Option<crate::tls::TlsConfig>,
+    /// If set, how long a session lasts after the last request.
+    ///
+    /// The session cookie is refreshed by requests made after half of this
+    /// time has passed. When the session has expired, requests are answered
+    /// with status 401 until the access token is given again, and its event
+    /// streams are closed. Callbacks sent over a WebSocket do not refresh the
+    /// cookie and are answered with an error once the session has expired.
+    /// If not set, sessions do not expire, and only the 10000 most recently
+    /// revoked sessions stay revoked.
+    pub session_lifetime: Option<std::time::Duration>,
+    /// If set, the largest number of event streams open at once.
+    ///
+    /// Further event streams are rejected with status 503.
+    pub max_event_streams: Option<usize>,
+    /// If set, the largest number of event streams open at once for each
+    /// session (i.e. browser).
+    ///
+    /// Further event streams are rejected with status 503.
+    pub max_event_streams_per_session: Option<usize>,
+    /// Further origins, such as `https://example.com`, of pages allowed to
+    /// open WebSockets.
+    ///
+    /// Browsers do not apply the same-origin policy to WebSockets, so
+    /// WebSocket requests made by a browser are rejected with status 403
+    /// unless the host of their `Origin` matches the `X-Forwarded-Host` set by
+    /// a reverse proxy, or the `Host` if there is none, or the origin is
+    /// listed here. Use this if a proxy in front of the server changes the
+    /// host without setting `X-Forwarded-Host`.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Config {
+    /// Create a configuration with default settings serving the files at
+    /// `serve_filepath` or, with the `bundle_files` feature, `bundled_files`.
+    pub fn new(
+        serve_filepath: &'static std::path::Path,
+        #[cfg(feature = "bundle_files")] bundled_files: &'static includedir::Files,
+    ) -> Self {
+        Self {
+            serve_filepath,
+            #[cfg(feature = "bundle_files")]
+            bundled_files,
+            channel_size: 10,
+            cookie_name: "client".into(),
+            base_path: "/".into(),
+            update_mode: UpdateMode::FullState,
+            replay_buffer_size: 100,
+            heartbeat_interval: None,
+            retry_interval: None,
+            overflow_policy: OverflowPolicy::CoalesceToLatest,
+            shutdown_timeout: std::time::Duration::from_secs(5),
+            tls: None,
+            session_lifetime: None,
+            max_event_streams: None,
+            max_event_streams_per_session: None,
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+/// How changes to the shared state are sent to connected clients.
+///
+/// Regardless of the mode, the first message on each new event stream
+/// contains the full state. In the patch modes, subsequent messages are sent
+/// with the event name given by [bui_backend_types::patch_event_name] and
+/// contain a patch which transforms the previously sent state into the new
+/// state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UpdateMode {
+    /// Send the full state on every change.
+    #[default]
+    FullState,
+    /// Send a [JSON Patch (RFC 6902)](https://tools.ietf.org/html/rfc6902)
+    /// document on every change.
+    JsonPatch,
+    /// Send a [JSON Merge Patch (RFC
+    /// 7386)](https://tools.ietf.org/html/rfc7386) document on every change.
+    ///
+    /// Note that merge patches cannot set a value to `null` because `null`
+    /// signifies removal of a field.
+    MergePatch,
+}
+
+/// What to do when a client does not keep up with the sent events.
+///
+/// Events are queued for each connection, so a slow client never delays
+/// sending to other clients. This determines what happens when the queue of
+/// a connection, which holds `Config::channel_size` events, is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued event.
+    ///
+    /// With patch update modes, a client missing an event no longer has the
+    /// correct state, so this can only be used with `UpdateMode::FullState`.
+    /// [crate::BuiAppBuilder::build] rejects other combinations.
+    DropOldest,
+    /// Replace all queued events with the full current state.
+    #[default]
+    CoalesceToLatest,
+    /// Close the connection of the slow client.
+    Disconnect,
+}
+
+/// Closes an event stream connection from the server side.
+#[derive(Clone, Debug, Default)]
+pub struct EventStreamCloser(Arc<tokio::sync::Notify>);
+
+impl EventStreamCloser {
+    /// Close the event stream.
+    ///
+    /// Chunks already sent to the [EventChunkSender] are still delivered to
+    /// the client, but further sends fail.
+    pub fn close(&self) {
+        self.0.notify_one();
+    }
+
+    /// Wait until `close()` is called.
+    async fn closed(self) {
+        self.0.notified().await
+    }
 }
 
 /// Wrapper around `hyper::body::Bytes` to enable sending data to clients.
@@ -71,6 +268,24 @@ pub struct NewEventStreamConnection {
     pub connection_key: ConnectionKey,
     /// The path being requested (starts with `BuiService::events_prefix`).
     pub path: String,
+    /// The id of the last event received by a reconnecting client.
+    pub last_event_id: Option<String>,
+    /// Closes the connection.
+    pub closer: EventStreamCloser,
+    /// Set if the client connected with a WebSocket, to which events are
+    /// sent encoded rather than as event stream chunks.
+    pub(crate) websocket: Option<WebSocketSender>,
+}
+
+/// Sends messages to a WebSocket.
+///
+/// Messages are encoded with the negotiated encoding before sending, so that
+/// each event is encoded once for all connections using the same encoding.
+/// An empty message is sent as a ping.
+#[derive(Debug, Clone)]
+pub(crate) struct WebSocketSender {
+    pub(crate) encoding: Encoding,
+    pub(crate) tx: mpsc::Sender<hyper::body::Bytes>,
 }
 
 type NewConnectionSender = mpsc::Sender<NewEventStreamConnection>;
@@ -83,7 +298,7 @@ pub type RawReqHandler = Arc<
     Box<
         dyn (Fn(
                 http::response::Builder,
-                http::Request<hyper::body::Incoming>,
+                http::Request<RequestBody>,
             ) -> Result<http::Response<MyBody>, http::Error>)
             + Send
             + Sync,
@@ -91,6 +306,9 @@ pub type RawReqHandler = Arc<
 >;
 
 /// Implement this trait to handle callbacks.
+///
+/// To send a response to the client, implement
+/// [CallbackHandlerWithResponse] instead.
 pub trait CallbackHandler: Send + dyn_clone::DynClone {
     /// The type of the callback-provided data.
     type Data;
@@ -98,14 +316,182 @@ pub trait CallbackHandler: Send + dyn_clone::DynClone {
     /// HTTP request to "/callback" has been made with payload which as been
     /// deserialized into `Self::Data` and session data stored in
     /// [CallbackDataAndSession].
+    #[allow(clippy::type_complexity)]
     fn call<'a>(
         &'a self,
         data_sess: CallbackDataAndSession<Self::Data>,
     ) -> Pin<Box<dyn Future<Output = Result<(), Box<dyn StdError + Send>>> + Send + 'a>>;
+
+    /// Decide whether the session may send this callback, before `call` is
+    /// called.
+    ///
+    /// Unauthorized callbacks are answered with status 403. By default, only
+    /// sessions with [Role::Controller] may send callbacks. Override this to
+    /// allow [Role::Viewer] some callbacks, or to restrict individual
+    /// callbacks further.
+    fn authorize(&self, data_sess: &CallbackDataAndSession<Self::Data>) -> bool {
+        data_sess.role == Role::Controller
+    }
 }
 
 dyn_clone::clone_trait_object!(<CB> CallbackHandler<Data = CB>);
 
+/// Implement this trait to handle callbacks and send a response to the
+/// client.
+///
+/// This is implemented for every [CallbackHandler], with the response `()`.
+pub trait CallbackHandlerWithResponse: Send + dyn_clone::DynClone {
+    /// The type of the callback-provided data.
+    type Data;
+
+    /// The type of the response sent to the client.
+    ///
+    /// The response to a callback sent over HTTP is encoded like the
+    /// callback payload. Over a WebSocket, it is sent as the data of a
+    /// [CALLBACK_REPLY_EVENT] event, in the order the callbacks were
+    /// received. Every response is sent, even one encoded as `null`.
+    type Response: Serialize + Send;
+
+    /// Handle a callback, see [CallbackHandler::call].
+    #[allow(clippy::type_complexity)]
+    fn call<'a>(
+        &'a self,
+        data_sess: CallbackDataAndSession<Self::Data>,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::Response, Box<dyn StdError + Send>>> + Send + 'a>>;
+
+    /// Decide whether the session may send this callback, see
+    /// [CallbackHandler::authorize].
+    fn authorize(&self, data_sess: &CallbackDataAndSession<Self::Data>) -> bool {
+        data_sess.role == Role::Controller
+    }
+
+    /// Whether the response is sent to the client.
+    ///
+    /// This is only `false` for a [CallbackHandler], whose callbacks are
+    /// answered with the JSON object `{}` over HTTP and not at all over a
+    /// WebSocket. Not part of the public API.
+    #[doc(hidden)]
+    fn sends_response(&self) -> bool {
+        true
+    }
+}
+
+impl<H: CallbackHandler> CallbackHandlerWithResponse for H {
+    type Data = H::Data;
+    type Response = ();
+
+    fn sends_response(&self) -> bool {
+        false
+    }
+
+    fn call<'a>(
+        &'a self,
+        data_sess: CallbackDataAndSession<Self::Data>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Box<dyn StdError + Send>>> + Send + 'a>> {
+        CallbackHandler::call(self, data_sess)
+    }
+
+    fn authorize(&self, data_sess: &CallbackDataAndSession<Self::Data>) -> bool {
+        CallbackHandler::authorize(self, data_sess)
+    }
+}
+
+/// A boxed [CallbackHandler], as passed to [launcher].
+struct BoxedCallbackHandler<CB>(Box<dyn Send + CallbackHandler<Data = CB>>);
+
+impl<CB> Clone for BoxedCallbackHandler<CB> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<CB> CallbackHandler for BoxedCallbackHandler<CB> {
+    type Data = CB;
+
+    fn call<'a>(
+        &'a self,
+        data_sess: CallbackDataAndSession<Self::Data>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Box<dyn StdError + Send>>> + Send + 'a>> {
+        self.0.call(data_sess)
+    }
+
+    fn authorize(&self, data_sess: &CallbackDataAndSession<Self::Data>) -> bool {
+        self.0.authorize(data_sess)
+    }
+}
+
+/// How the response to a callback is sent.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ReplyFormat {
+    /// In the body of the HTTP response.
+    Http(Encoding),
+    /// As a WebSocket message.
+    WebSocket(Encoding),
+}
+
+impl ReplyFormat {
+    fn encode<R: Serialize>(self, response: &R) -> Result<Vec<u8>, EncodingError> {
+        match self {
+            ReplyFormat::Http(encoding) => encoding.encode(response),
+            ReplyFormat::WebSocket(encoding) => {
+                websocket_event(encoding, None, CALLBACK_REPLY_EVENT, response)
+            }
+        }
+    }
+}
+
+/// A [CallbackHandlerWithResponse] with its response encoded.
+pub(crate) trait DynCallbackHandler: Send + dyn_clone::DynClone {
+    type Data;
+
+    /// Handle a callback and encode the response, which is `None` for a
+    /// [CallbackHandler].
+    #[allow(clippy::type_complexity)]
+    fn call_dyn<'a>(
+        &'a self,
+        data_sess: CallbackDataAndSession<Self::Data>,
+        reply: ReplyFormat,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>, Box<dyn StdError + Send>>> + Send + 'a>>;
+}
+
+dyn_clone::clone_trait_object!(<CB> DynCallbackHandler<Data = CB>);
+
+impl<H: CallbackHandlerWithResponse> DynCallbackHandler for H {
+    type Data = H::Data;
+
+    fn call_dyn<'a>(
+        &'a self,
+        data_sess: CallbackDataAndSession<Self::Data>,
+        reply: ReplyFormat,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>, Box<dyn StdError + Send>>> + Send + 'a>>
+    {
+        if !self.authorize(&data_sess) {
+            warn!(
+                "session {:?} with role {:?} not authorized to send callback",
+                data_sess.session_key, data_sess.role
+            );
+            let e = CallbackError::forbidden("not authorized to send this callback");
+            return Box::pin(futures::future::ready(Err(
+                Box::new(e) as Box<dyn StdError + Send>
+            )));
+        }
+        let sends_response = self.sends_response();
+        let fut = self.call(data_sess);
+        Box::pin(async move {
+            let response = fut.await?;
+            if !sends_response {
+                return Ok(None);
+            }
+            // An encoding error is returned like an error of the handler, so
+            // it is answered with status 500.
+            let buf = reply
+                .encode(&response)
+                .map_err(|e| Box::new(e) as Box<dyn StdError + Send>)?;
+            Ok(Some(buf))
+        })
+    }
+}
+
 /// A dummy callback handler that does nothing.
 ///
 /// This is provided as an example.
@@ -143,19 +529,181 @@ impl CallbackHandler for ErrorCallbackHandler {
     }
 }
 
+/// An error returned by a [CallbackHandler] which is shown to the client.
+///
+/// The client receives the HTTP status and a JSON body of the form
+/// `{"errors": [message], "details": details}`, where `details` is omitted if
+/// not set. Callbacks sent over a WebSocket receive the same body in an event
+/// named [CALLBACK_ERROR_EVENT]. Any other error returned by a callback
+/// handler results in status 500 and the message "internal server error".
+///
+/// Return it from [CallbackHandler::call] as
+/// `Box::new(CallbackError::bad_request("...")) as Box<dyn StdError + Send>`.
+#[derive(Debug, Clone)]
+pub struct CallbackError {
+    status: StatusCode,
+    message: String,
+    details: Option<serde_json::Value>,
+}
+
+impl CallbackError {
+    /// Create an error with the given HTTP status and message.
+    pub fn new<M: Into<String>>(status: StatusCode, message: M) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// Create an error with status 400 (Bad Request).
+    pub fn bad_request<M: Into<String>>(message: M) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    /// Create an error with status 403 (Forbidden).
+    pub fn forbidden<M: Into<String>>(message: M) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message)
+    }
+
+    /// Create an error with status 409 (Conflict).
+    pub fn conflict<M: Into<String>>(message: M) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+
+    /// Create an error with status 422 (Unprocessable Entity).
+    pub fn unprocessable_entity<M: Into<String>>(message: M) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, message)
+    }
+
+    /// Create an error with status 500 (Internal Server Error).
+    pub fn internal<M: Into<String>>(message: M) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    /// Attach details, such as a list of validation failures.
+    ///
+    /// Fails if `details` cannot be serialized to JSON, for example a map
+    /// with keys which are not strings.
+    pub fn with_details<D: Serialize>(mut self, details: D) -> Result<Self, serde_json::Error> {
+        self.details = Some(serde_json::to_value(details)?);
+        Ok(self)
+    }
+
+    /// The HTTP status.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// The message shown to the client.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The details, if any.
+    pub fn details(&self) -> Option<&serde_json::Value> {
+        self.details.as_ref()
+    }
+}
+
+impl std::fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{} ({})", self.message, self.status)
+    }
+}
+
+impl StdError for CallbackError {}
+
+/// Counts an event stream as open until dropped.
+struct StreamPermit {
+    sessions: Arc<Mutex<SessionRegistry>>,
+    session_key: SessionKey,
+    connection_key: ConnectionKey,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.sessions
+            .lock()
+            .remove_stream(&self.session_key, &self.connection_key);
+    }
+}
+
+/// Why a new event stream was not opened.
+#[derive(Debug)]
+pub(crate) enum StreamRejected {
+    /// `Config::max_event_streams` are open.
+    TooMany,
+    /// `Config::max_event_streams_per_session` are open for the session.
+    TooManyForSession,
+    /// The application is not keeping up with new connections.
+    Busy,
+    /// The application no longer receives new connections.
+    Closed,
+    /// The session was revoked while the request was handled.
+    SessionEnded,
+}
+
+impl std::fmt::Display for StreamRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            StreamRejected::TooMany => "too many event streams are open",
+            StreamRejected::TooManyForSession => "too many event streams are open for this session",
+            StreamRejected::Busy => "server is busy",
+            StreamRejected::Closed => "server is not accepting event streams",
+            StreamRejected::SessionEnded => "session has ended",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Respond with status 503 to a rejected event stream.
+fn stream_rejected_response(
+    resp: http::response::Builder,
+    rejected: StreamRejected,
+) -> Result<http::Response<MyBody>, http::Error> {
+    warn!("rejecting event stream: {}", rejected);
+    let e = ErrorsBackToBrowser::new(rejected.to_string());
+    let body_buf = serde_json::to_vec(&e).unwrap();
+    let mut resp = resp
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(hyper::header::CONTENT_TYPE, JSON_TYPE);
+    if let StreamRejected::Busy = rejected {
+        resp = resp.header(hyper::header::RETRY_AFTER, "1");
+    }
+    resp.body(body_from_buf(&body_buf))
+}
+
+/// Holds the callback handler, which need not be `Sync`, such that
+/// [BuiService] is `Sync`.
+struct CallbackHandlerCell<CB>(Mutex<Box<dyn DynCallbackHandler<Data = CB>>>);
+
+impl<CB> CallbackHandlerCell<CB> {
+    fn get(&self) -> Box<dyn DynCallbackHandler<Data = CB>> {
+        self.0.lock().clone()
+    }
+}
+
+impl<CB> Clone for CallbackHandlerCell<CB> {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.get()))
+    }
+}
+
 /// Handle HTTP requests and coordinate responses to data updates.
 ///
 /// Implements `hyper::server::Service` to act as HTTP server and handle requests.
 #[derive(Clone)]
 pub struct BuiService<CB> {
     config: Config,
-    callback_handler: Box<dyn Send + CallbackHandler<Data = CB>>,
+    callback_handler: CallbackHandlerCell<CB>,
+    sessions: Arc<Mutex<SessionRegistry>>,
+    login_throttle: Arc<Mutex<LoginThrottle>>,
     next_connection_key: Arc<Mutex<ConnectionKey>>,
-    jwt_secret: Vec<u8>,
-    encoding_key: jsonwebtoken::EncodingKey,
-    valid_token: AccessToken,
+    access: SharedAccess,
     tx_new_connection: NewConnectionSender,
     events_prefix: String,
+    base_path: String,
     raw_req_handler: Option<RawReqHandler>,
 }
 
@@ -209,6 +757,35 @@ impl<CB> BuiService<CB> {
         &self.events_prefix
     }
 
+    /// Get the path under which the BUI is served.
+    ///
+    /// This is `Config::base_path` without a trailing slash, so it is empty
+    /// when serving at the root.
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    /// Get the configuration settings.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Get a `BuiService` which applies the access control `auth` to
+    /// requests.
+    ///
+    /// Everything else, including the callback handler and the new event
+    /// stream connections, is shared with `self`. This allows serving several
+    /// listeners with different access control.
+    pub fn with_access_control(&self, auth: &access_control::AccessControl) -> Self
+    where
+        CB: Clone,
+    {
+        Self {
+            access: SharedAccess::new(auth),
+            ..self.clone()
+        }
+    }
+
     fn get_next_connection_key(&self) -> ConnectionKey {
         let mut nk = self.next_connection_key.lock();
         let result = *nk;
@@ -216,35 +793,295 @@ impl<CB> BuiService<CB> {
         result
     }
 
+    /// Create a new event stream connection and inform the listener about it.
+    ///
+    /// This does not wait: if a limit on the number of event streams is
+    /// reached or the listener is not keeping up, the event stream is
+    /// rejected.
+    fn new_event_stream(
+        &self,
+        session_key: SessionKey,
+        path: &str,
+        last_event_id: Option<String>,
+        websocket: Option<WebSocketSender>,
+    ) -> Result<
+        (
+            EventChunkSender,
+            impl futures::Stream<Item = hyper::body::Bytes> + Send + Sync + Unpin + 'static,
+        ),
+        StreamRejected,
+    > {
+        let connection_key = self.get_next_connection_key();
+        let closer = EventStreamCloser::default();
+        self.sessions.lock().add_stream(
+            session_key,
+            connection_key,
+            closer.clone(),
+            self.config.max_event_streams,
+            self.config.max_event_streams_per_session,
+        )?;
+        let permit = StreamPermit {
+            sessions: self.sessions.clone(),
+            session_key,
+            connection_key,
+        };
+        let (tx_event_stream, rx_event_stream) = mpsc::channel(self.config.channel_size);
+
+        // The stream ends when either all senders are dropped or the
+        // connection is closed by the server or the session expires, and
+        // buffered chunks are sent.
+        // It holds the permit, so it is counted as open until dropped.
+        let closed = {
+            let closed = Box::pin(closer.clone().closed());
+            let expired = Box::pin(session_expired(self.sessions.clone(), session_key));
+            async move {
+                futures::future::select(closed, expired).await;
+            }
+        };
+        let rx_event_stream = Box::pin(futures::stream::unfold(
+            (rx_event_stream, Some(Box::pin(closed)), permit),
+            |(mut rx, mut closed, permit)| async move {
+                if let Some(closed_fut) = closed.take() {
+                    let recv_fut = Box::pin(rx.recv());
+                    let received = match futures::future::select(recv_fut, closed_fut).await {
+                        futures::future::Either::Left((chunk, closed_fut)) => {
+                            Some((chunk, closed_fut))
+                        }
+                        futures::future::Either::Right(_) => None,
+                    };
+                    match received {
+                        Some((chunk, closed_fut)) => {
+                            return chunk.map(|chunk| (chunk, (rx, Some(closed_fut), permit)));
+                        }
+                        None => rx.close(),
+                    }
+                }
+                rx.recv().await.map(|chunk| (chunk, (rx, closed, permit)))
+            },
+        ));
+
+        let conn_info = NewEventStreamConnection {
+            chunk_sender: tx_event_stream.clone(),
+            session_key,
+            connection_key,
+            path: path.to_string(),
+            last_event_id,
+            closer,
+            websocket,
+        };
+
+        match self.tx_new_connection.try_send(conn_info) {
+            Ok(()) => Ok((tx_event_stream, rx_event_stream)),
+            Err(mpsc::error::TrySendError::Full(_)) => Err(StreamRejected::Busy),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(StreamRejected::Closed),
+        }
+    }
+
     fn do_set_cookie_x(
         &self,
         resp: http::response::Builder,
-    ) -> (http::response::Builder, SessionKey) {
+        auth: &SessionAuth,
+    ) -> (http::response::Builder, SessionKey, usize) {
         // There was no valid client key in the HTTP header, so generate a
         // new one and set it on client.
         let session_key = SessionKey::new();
+        let (resp, exp) = self.set_session_cookie(resp, session_key, auth);
+        (resp, session_key, exp)
+    }
+
+    /// Get the session key of a login, setting the session cookie if the
+    /// session is new or due to be refreshed, and record the request in the
+    /// session registry.
+    fn start_session(
+        &self,
+        login_info: ValidLogin,
+        resp: http::response::Builder,
+        remote_addr: Option<Address>,
+    ) -> (http::response::Builder, SessionKey, SessionAuth) {
+        let (resp, session_key, auth, exp) = self.start_session_cookie(login_info, resp);
+        // The cookie is valid until the end of the second `exp`.
+        let expires = self
+            .config
+            .session_lifetime
+            .map(|_| std::time::UNIX_EPOCH + std::time::Duration::from_secs(exp as u64 + 1));
+        self.sessions.lock().seen(
+            session_key,
+            remote_addr,
+            auth.identity.as_ref(),
+            auth.role,
+            expires,
+        );
+        (resp, session_key, auth)
+    }
+
+    fn start_session_cookie(
+        &self,
+        login_info: ValidLogin,
+        resp: http::response::Builder,
+    ) -> (http::response::Builder, SessionKey, SessionAuth, usize) {
+        match login_info {
+            ValidLogin::NeedsSessionKey { auth } => {
+                let (resp, key, exp) = self.do_set_cookie_x(resp, &auth);
+                (resp, key, auth, exp)
+            }
+            ValidLogin::ExistingSession {
+                key,
+                exp,
+                auth,
+                stale_key,
+            } => {
+                let refresh = stale_key
+                    || match self.config.session_lifetime {
+                        Some(lifetime) => {
+                            exp.saturating_sub(unix_time_secs()) < lifetime.as_secs() as usize / 2
+                        }
+                        None => false,
+                    };
+                if refresh {
+                    debug!("refreshing session {:?}", key);
+                    let (resp, exp) = self.set_session_cookie(resp, key, &auth);
+                    (resp, key, auth, exp)
+                } else {
+                    (resp, key, auth, exp)
+                }
+            }
+        }
+    }
+
+    /// List the sessions seen by this server.
+    ///
+    /// Sessions are forgotten once expired or, without
+    /// [Config::session_lifetime], a day after their last request. Of more
+    /// than 10000 sessions, the least recently seen without event streams are
+    /// forgotten.
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions.lock().list()
+    }
+
+    /// Forget sessions which have expired.
+    ///
+    /// This is done periodically by [crate::highlevel].
+    pub(crate) fn prune_sessions(&self) {
+        self.sessions.lock().prune(self.config.session_lifetime);
+    }
+
+    /// End the session `session_key`, closing its event streams.
+    ///
+    /// Further requests with the session cookie are rejected as if the
+    /// session had expired, or start a new session if access is not
+    /// restricted.
+    /// Returns whether the session was known.
+    pub fn revoke_session(&self, session_key: SessionKey) -> bool {
+        self.sessions.lock().revoke(session_key)
+    }
+
+    /// End all sessions, closing their event streams.
+    pub(crate) fn revoke_all_sessions(&self) {
+        self.sessions.lock().revoke_all();
+    }
+
+    /// The access control in effect, which may be changed while serving.
+    pub(crate) fn access(&self) -> &SharedAccess {
+        &self.access
+    }
+
+    /// Get a cookie which makes the browser delete the session cookie.
+    fn removal_cookie(&self) -> cookie::Cookie<'static> {
+        let mut c = cookie::Cookie::new(self.config.cookie_name.clone(), "");
+        c.set_path(self.cookie_path());
+        c.make_removal();
+        c
+    }
+
+    fn cookie_path(&self) -> String {
+        if self.base_path.is_empty() {
+            "/".to_string()
+        } else {
+            self.base_path.clone()
+        }
+    }
+
+    fn set_session_cookie(
+        &self,
+        resp: http::response::Builder,
+        session_key: SessionKey,
+        auth: &SessionAuth,
+    ) -> (http::response::Builder, usize) {
+        // The cookie itself does not expire, so that the browser keeps
+        // sending it and can be told that the session has expired.
+        let exp = match self.config.session_lifetime {
+            Some(lifetime) => unix_time_secs() + lifetime.as_secs() as usize,
+            None => 10000000000,
+        };
         let claims = JwtClaims {
             key: session_key,
-            exp: 10000000000,
+            auth: auth.clone(),
+            exp,
         };
 
         let token = {
             jsonwebtoken::encode(
                 &jsonwebtoken::Header::default(),
                 &claims,
-                &self.encoding_key,
+                &self.access.encoding_key(),
             )
             .unwrap()
         };
         let mut c = cookie::Cookie::new(self.config.cookie_name.clone(), token);
         c.set_same_site(cookie::SameSite::Strict);
         c.set_http_only(true);
+        c.set_path(self.cookie_path());
+        if self.config.tls.is_some() {
+            c.set_secure(true);
+        }
         let resp = resp.header(
             hyper::header::SET_COOKIE,
             hyper::header::HeaderValue::from_str(&c.to_string()).unwrap(),
         );
-        (resp, session_key)
+        (resp, exp)
+    }
+}
+
+/// Wait until the session has expired.
+///
+/// Without [Config::session_lifetime], sessions do not expire. Revoked
+/// sessions have their event streams closed when revoked.
+async fn session_expired(sessions: Arc<Mutex<SessionRegistry>>, session_key: SessionKey) {
+    // The expiry moves when the session cookie is refreshed.
+    loop {
+        let expires = sessions.lock().expires(&session_key);
+        let remaining = match expires {
+            Some(expires) => match expires.duration_since(std::time::SystemTime::now()) {
+                Ok(remaining) if !remaining.is_zero() => remaining,
+                _ => {
+                    debug!("session {:?} expired", session_key);
+                    return;
+                }
+            },
+            None => return futures::future::pending().await,
+        };
+        tokio::time::sleep(remaining).await;
+    }
+}
+
+/// Why callbacks of a session with an open WebSocket are rejected, if the
+/// session has ended since the WebSocket was opened.
+fn session_ended_msg(sessions: &SessionRegistry, session_key: &SessionKey) -> Option<&'static str> {
+    if sessions.is_revoked(session_key) {
+        return Some(SESSION_REVOKED_MSG);
     }
+    match sessions.expires(session_key) {
+        Some(expires) if expires <= std::time::SystemTime::now() => Some(SESSION_EXPIRED_MSG),
+        _ => None,
+    }
+}
+
+/// The current time as seconds since the Unix epoch, as used in [JwtClaims].
+fn unix_time_secs() -> usize {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as usize)
+        .unwrap_or(0)
 }
 
 fn body_from_buf(body_buf: &[u8]) -> MyBody {
@@ -255,20 +1092,18 @@ fn body_from_buf(body_buf: &[u8]) -> MyBody {
 
 async fn handle_req<CB>(
     self_: BuiService<CB>,
-    req: http::Request<hyper::body::Incoming>,
+    req: http::Request<RequestBody>,
     mut resp: http::response::Builder,
     login_info: ValidLogin,
     raw_req_handler: Option<RawReqHandler>,
-) -> Result<http::Response<MyBody>, http::Error> {
+) -> Result<http::Response<MyBody>, http::Error>
+where
+    CB: 'static + serde::de::DeserializeOwned + Send,
+{
     // TODO: convert this to be async yield when blocking on IO operations.
-    let session_key = match login_info {
-        ValidLogin::NeedsSessionKey => {
-            let (resp2, session_key) = self_.do_set_cookie_x(resp);
-            resp = resp2;
-            session_key
-        }
-        ValidLogin::ExistingSession(k) => k,
-    };
+    let remote_addr = req.extensions().get::<Address>().cloned();
+    let (resp2, session_key, auth) = self_.start_session(login_info, resp, remote_addr);
+    resp = resp2;
 
     let resp_final = match (req.method(), req.uri().path()) {
         (&Method::GET, path) => {
@@ -290,31 +1125,16 @@ async fn handle_req<CB>(
                     }
                 }
 
-                if accepts_event_stream {
-                    let connection_key = self_.get_next_connection_key();
-                    let (tx_event_stream, rx_event_stream) =
-                        mpsc::channel(self_.config.channel_size);
-
+                if is_websocket_upgrade(&req) {
+                    let path = path.to_string();
+                    handle_websocket_upgrade(self_, req, resp, session_key, auth, &path).await?
+                } else if accepts_event_stream {
+                    let last_event_id = get_last_event_id(&req);
                     let rx_event_stream =
-                        tokio_stream::wrappers::ReceiverStream::new(rx_event_stream);
-
-                    {
-                        let conn_info = NewEventStreamConnection {
-                            chunk_sender: tx_event_stream,
-                            session_key,
-                            connection_key,
-                            path: path.to_string(),
-                        };
-
-                        let send_future = self_.tx_new_connection.send(conn_info);
-                        match send_future.await {
-                            Ok(()) => {}
-                            Err(e) => {
-                                error!("failed to send new connection info: {:?}", e);
-                                // should we panic here?
-                            }
+                        match self_.new_event_stream(session_key, path, last_event_id, None) {
+                            Ok((_, rx_event_stream)) => rx_event_stream,
+                            Err(rejected) => return stream_rejected_response(resp, rejected),
                         };
-                    }
 
                     resp = resp.header(
                         hyper::header::CONTENT_TYPE,
@@ -335,7 +1155,7 @@ async fn handle_req<CB>(
                         'text/event-stream'"
                         .to_string();
                     warn!("{}", estr);
-                    let e = ErrorsBackToBrowser { errors: vec![estr] };
+                    let e = ErrorsBackToBrowser::new(estr);
                     let body_buf = serde_json::to_vec(&e).unwrap();
                     resp = resp.status(StatusCode::BAD_REQUEST);
                     resp.body(body_from_buf(&body_buf))?
@@ -381,69 +1201,616 @@ async fn handle_req<CB>(
     Ok(resp_final)
 }
 
-fn handle_callback<CB>(
-    handler: Box<dyn CallbackHandler<Data = CB> + Send>,
-    session_key: bui_backend_types::SessionKey,
-    resp0: http::response::Builder,
-    req: http::Request<hyper::body::Incoming>,
-) -> Pin<Box<dyn Future<Output = Result<http::Response<MyBody>, hyper::Error>> + Send>>
+/// Get the id of the last event received by a reconnecting client.
+///
+/// Browsers send this in the `Last-Event-ID` header when an `EventSource`
+/// reconnects. As WebSocket clients cannot set headers, the `last_event_id`
+/// query parameter is also accepted.
+fn get_last_event_id<B>(req: &http::Request<B>) -> Option<String> {
+    if let Some(value) = req.headers().get("last-event-id") {
+        return value.to_str().ok().map(|v| v.to_string());
+    }
+    let query = req.uri().query().unwrap_or("");
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "last_event_id")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Check if the request asks to upgrade the connection to a WebSocket.
+fn is_websocket_upgrade<B>(req: &http::Request<B>) -> bool {
+    let has_token = |name: hyper::header::HeaderName, token: &str| {
+        req.headers().get_all(name).iter().any(|value| {
+            value
+                .to_str()
+                .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+                .unwrap_or(false)
+        })
+    };
+    has_token(hyper::header::CONNECTION, "upgrade")
+        && has_token(hyper::header::UPGRADE, "websocket")
+}
+
+/// Check that a request was made from a page served by us.
+///
+/// Browsers do not apply the same-origin policy to WebSockets, so this
+/// protects against cross-site WebSocket hijacking. Requests without an
+/// `Origin` header do not come from a browser and are allowed. Otherwise, the
+/// origin must be one of `allowed_origins` or its host must be the host the
+/// browser connected to. Behind a reverse proxy, this is the first host in
+/// `X-Forwarded-Host` rather than `Host`. Browsers do not let pages set
+/// either header on WebSocket requests.
+fn origin_allowed<B>(req: &http::Request<B>, allowed_origins: &[String]) -> bool {
+    let origin = match req.headers().get(hyper::header::ORIGIN) {
+        Some(origin) => match origin.to_str() {
+            Ok(origin) => origin,
+            Err(_) => return false,
+        },
+        None => return true,
+    };
+    if allowed_origins
+        .iter()
+        .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    {
+        return true;
+    }
+    let host = req
+        .headers()
+        .get("x-forwarded-host")
+        .or_else(|| req.headers().get(hyper::header::HOST))
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.split(',').next())
+        .map(str::trim);
+    match (origin.split("://").nth(1), host) {
+        (Some(origin_host), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+        _ => false,
+    }
+}
+
+/// The tasks serving the WebSockets upgraded from one connection.
+///
+/// [crate::highlevel] adds this to requests, so that it can wait for the
+/// WebSockets upon shutdown. Without it, WebSockets are served by tasks of
+/// their own.
+#[derive(Clone, Default)]
+pub(crate) struct WebSocketTasks(Arc<Mutex<tokio::task::JoinSet<()>>>);
+
+impl WebSocketTasks {
+    fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        self.0.lock().spawn(task);
+    }
+
+    /// Wait for the tasks spawned so far to finish.
+    ///
+    /// If this future is dropped, the tasks are aborted.
+    pub(crate) async fn join(&self) {
+        let mut tasks = std::mem::take(&mut *self.0.lock());
+        while tasks.join_next().await.is_some() {}
+    }
+}
+
+async fn handle_websocket_upgrade<CB>(
+    self_: BuiService<CB>,
+    req: http::Request<RequestBody>,
+    mut resp: http::response::Builder,
+    session_key: SessionKey,
+    auth: SessionAuth,
+    path: &str,
+) -> Result<http::Response<MyBody>, http::Error>
 where
     CB: 'static + serde::de::DeserializeOwned + Send,
 {
-    let result = async move {
-        let body = req.into_body();
-        let chunks: Result<http_body_util::Collected<bytes::Bytes>, hyper::Error> = {
+    if !origin_allowed(&req, &self_.config.allowed_origins) {
+        let estr = "WebSocket request origin is not allowed".to_string();
+        warn!("{}", estr);
+        let e = ErrorsBackToBrowser::new(estr);
+        let body_buf = serde_json::to_vec(&e).unwrap();
+        resp = resp.status(StatusCode::FORBIDDEN);
+        return resp.body(body_from_buf(&body_buf));
+    }
+
+    let version_ok = req
+        .headers()
+        .get(hyper::header::SEC_WEBSOCKET_VERSION)
+        .map(|v| v == "13")
+        .unwrap_or(false);
+    let accept_key = match req.headers().get(hyper::header::SEC_WEBSOCKET_KEY) {
+        Some(key) if version_ok => derive_accept_key(key.as_bytes()),
+        _ => {
+            let estr = "WebSocket request does not specify a key or \
+                uses an unsupported version"
+                .to_string();
+            warn!("{}", estr);
+            let e = ErrorsBackToBrowser::new(estr);
+            let body_buf = serde_json::to_vec(&e).unwrap();
+            resp = resp.status(StatusCode::BAD_REQUEST);
+            return resp.body(body_from_buf(&body_buf));
+        }
+    };
+
+    // Use the first requested subprotocol which selects a known encoding.
+    let encoding = req
+        .headers()
+        .get_all(hyper::header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(Encoding::from_websocket_protocol);
+    if let Some(encoding) = encoding {
+        resp = resp.header(
+            hyper::header::SEC_WEBSOCKET_PROTOCOL,
+            encoding.websocket_protocol(),
+        );
+    }
+    let encoding = encoding.unwrap_or(Encoding::Json);
+
+    let last_event_id = get_last_event_id(&req);
+    let (tx_messages, rx_messages) = mpsc::channel(self_.config.channel_size);
+    let websocket = WebSocketSender {
+        encoding,
+        tx: tx_messages,
+    };
+    let (tx_event_stream, rx_event_stream) =
+        match self_.new_event_stream(session_key, path, last_event_id, Some(websocket.clone())) {
+            Ok(stream) => stream,
+            Err(rejected) => return stream_rejected_response(resp, rejected),
+        };
+    let callback_handler = self_.callback_handler.get();
+    let sessions = self_.sessions.clone();
+    let tasks = req.extensions().get::<WebSocketTasks>().cloned();
+    let on_upgrade = hyper::upgrade::on(req);
+
+    let task = async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let socket = hyper_util::rt::TokioIo::new(upgraded);
+                let ws = WebSocketStream::from_raw_socket(socket, WsRole::Server, None).await;
+                serve_websocket(
+                    ws,
+                    websocket,
+                    rx_messages,
+                    tx_event_stream,
+                    rx_event_stream,
+                    callback_handler,
+                    sessions,
+                    session_key,
+                    auth,
+                )
+                .await;
+            }
+            Err(e) => {
+                error!("failed to upgrade to WebSocket: {:?}", e);
+            }
+        }
+    };
+    match tasks {
+        Some(tasks) => tasks.spawn(task),
+        None => {
+            tokio::spawn(task);
+        }
+    }
+
+    resp.status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(hyper::header::CONNECTION, "upgrade")
+        .header(hyper::header::UPGRADE, "websocket")
+        .header(hyper::header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .body(body_from_buf(&[]))
+}
+
+/// Send messages and event stream chunks to a WebSocket and handle callbacks
+/// from it.
+///
+/// `rx_messages` receives messages already encoded for the WebSocket, such
+/// as the state pushed by [crate::highlevel]. Chunks sent by the application
+/// to the event stream are converted with [event_stream_chunk_to_websocket].
+/// With a binary encoding, messages are sent in binary frames and binary
+/// frames received are decoded as callbacks. Text frames are always JSON.
+///
+/// Once either the WebSocket or the event stream is closed, no further
+/// callbacks are read, but those in progress are completed and their replies
+/// sent before the WebSocket is closed.
+#[allow(clippy::too_many_arguments)]
+async fn serve_websocket<S, CB>(
+    ws: WebSocketStream<S>,
+    websocket: WebSocketSender,
+    mut rx_messages: mpsc::Receiver<hyper::body::Bytes>,
+    tx_event_stream: EventChunkSender,
+    rx_event_stream: impl futures::Stream<Item = hyper::body::Bytes> + Unpin,
+    handler: Box<dyn DynCallbackHandler<Data = CB>>,
+    sessions: Arc<Mutex<SessionRegistry>>,
+    session_key: SessionKey,
+    auth: SessionAuth,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    CB: serde::de::DeserializeOwned,
+{
+    use futures::SinkExt;
+
+    let encoding = websocket.encoding;
+    let (mut ws_tx, mut ws_rx) = futures::StreamExt::split(ws);
+    // Tell upstream to stop reading callbacks, and downstream that upstream
+    // is done.
+    let (closing, closing_wire) = stream_cancel::Tripwire::new();
+    let (upstream_done, upstream_done_wire) = stream_cancel::Tripwire::new();
+    let mut rx_event_stream =
+        futures::StreamExt::take_until(rx_event_stream, upstream_done_wire.clone());
+
+    let downstream = async move {
+        let _closing = closing;
+        // The event stream ends when the connection is closed, after which
+        // the messages already queued are still sent.
+        loop {
+            let next = match futures::future::select(
+                Box::pin(rx_messages.recv()),
+                futures::StreamExt::next(&mut rx_event_stream),
+            )
+            .await
+            {
+                futures::future::Either::Left((msg, _)) => futures::future::Either::Left(msg),
+                futures::future::Either::Right((chunk, _)) => futures::future::Either::Right(chunk),
+            };
+            let messages = match next {
+                futures::future::Either::Left(Some(msg)) => vec![msg],
+                futures::future::Either::Left(None) => break,
+                futures::future::Either::Right(Some(chunk)) => {
+                    let messages = event_stream_chunk_to_websocket(&chunk, encoding);
+                    if messages.is_empty() {
+                        // Send heartbeats as pings.
+                        vec![hyper::body::Bytes::new()]
+                    } else {
+                        messages
+                    }
+                }
+                futures::future::Either::Right(None) => break,
+            };
+            if let Err(e) = send_websocket_messages(&mut ws_tx, encoding, messages).await {
+                info!(
+                    "Failed to send to WebSocket, client probably disconnected. {:?}",
+                    e
+                );
+                return;
+            }
+        }
+        drop(rx_event_stream);
+
+        // Send the replies to callbacks in progress.
+        drop(_closing);
+        let mut upstream_done = Box::pin(upstream_done_wire);
+        loop {
+            let msg =
+                match futures::future::select(Box::pin(rx_messages.recv()), upstream_done).await {
+                    futures::future::Either::Left((Some(msg), done)) => {
+                        upstream_done = done;
+                        msg
+                    }
+                    _ => break,
+                };
+            if let Err(e) = send_websocket_messages(&mut ws_tx, encoding, vec![msg]).await {
+                info!(
+                    "Failed to send to WebSocket, client probably disconnected. {:?}",
+                    e
+                );
+                return;
+            }
+        }
+        let mut rest = vec![];
+        while let Ok(msg) = rx_messages.try_recv() {
+            rest.push(msg);
+        }
+        if let Err(e) = send_websocket_messages(&mut ws_tx, encoding, rest).await {
+            info!(
+                "Failed to send to WebSocket, client probably disconnected. {:?}",
+                e
+            );
+        }
+        ws_tx.close().await.ok();
+    };
+
+    let upstream = async move {
+        // Holding the sender keeps the event stream open if the application
+        // drops its sender.
+        let _tx_event_stream = tx_event_stream;
+        let _upstream_done = upstream_done;
+        let mut closing = Box::pin(closing_wire);
+        loop {
+            let msg = match futures::future::select(futures::StreamExt::next(&mut ws_rx), closing)
+                .await
+            {
+                futures::future::Either::Left((Some(msg), c)) => {
+                    closing = c;
+                    msg
+                }
+                _ => break,
+            };
+            let decoded = match msg {
+                Ok(Message::Text(buf)) => Encoding::Json
+                    .decode::<CB>(buf.as_bytes())
+                    .map_err(|e| (Encoding::Json, e)),
+                Ok(Message::Binary(buf)) => encoding.decode::<CB>(&buf).map_err(|e| (encoding, e)),
+                Ok(Message::Close(_)) => break,
+                Ok(_) => continue,
+                Err(e) => {
+                    info!("WebSocket error, client probably disconnected. {:?}", e);
+                    break;
+                }
+            };
+
+            // The session may have ended while the WebSocket is closing.
+            let ended = session_ended_msg(&sessions.lock(), &session_key);
+            // See `handle_callback` regarding the conversion to `CB`.
+            let errors = match (decoded, ended) {
+                (Ok(_), Some(estr)) => {
+                    warn!("callback from ended session {:?}: {}", session_key, estr);
+                    Some(ErrorsBackToBrowser::new(estr.to_string()))
+                }
+                (Ok(payload), None) => {
+                    let args2 = auth.clone().callback_data(payload, session_key);
+                    let reply = ReplyFormat::WebSocket(encoding);
+                    match handler.call_dyn(args2, reply).await {
+                        Ok(Some(msg)) => {
+                            if websocket.tx.send(msg.into()).await.is_err() {
+                                break;
+                            }
+                            None
+                        }
+                        Ok(None) => None,
+                        Err(e) => Some(callback_error_response(e).1),
+                    }
+                }
+                (Err((encoding, e)), _) => {
+                    let estr = parse_err_msg(encoding, &e);
+                    warn!("{}", estr);
+                    Some(ErrorsBackToBrowser::new(estr))
+                }
+            };
+
+            if let Some(e) = errors {
+                let msg =
+                    websocket_event(encoding, None, CALLBACK_ERROR_EVENT, &e).expect("encode");
+                if websocket.tx.send(msg.into()).await.is_err() {
+                    break;
+                }
+            }
+        }
+    };
+
+    futures::future::join(downstream, upstream).await;
+}
+
+/// Send encoded messages to a WebSocket, see [WebSocketSender].
+async fn send_websocket_messages<S>(
+    ws_tx: &mut S,
+    encoding: Encoding,
+    messages: Vec<hyper::body::Bytes>,
+) -> Result<(), tokio_tungstenite::tungstenite::Error>
+where
+    S: futures::Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    use futures::SinkExt;
+
+    for msg in messages {
+        let msg = if msg.is_empty() {
+            Message::Ping(Default::default())
+        } else if encoding.is_binary() {
+            Message::Binary(msg)
+        } else {
+            Message::Text(std::convert::TryFrom::try_from(msg).expect("utf8"))
+        };
+        ws_tx.send(msg).await?;
+    }
+    Ok(())
+}
+
+/// Encode an event as a WebSocket message.
+///
+/// With a binary `encoding`, the message is a [BinaryWebSocketEvent] and
+/// otherwise a [WebSocketEvent] with `data` as JSON.
+pub(crate) fn websocket_event<D: Serialize>(
+    encoding: Encoding,
+    id: Option<&str>,
+    event: &str,
+    data: &D,
+) -> Result<Vec<u8>, EncodingError> {
+    if encoding.is_binary() {
+        encoding.encode(&BinaryWebSocketEvent {
+            id: id.map(Into::into),
+            event: event.into(),
+            data,
+        })
+    } else {
+        let data = String::from_utf8(Encoding::Json.encode(data)?).expect("utf8");
+        Encoding::Json.encode(&WebSocketEvent {
+            id: id.map(Into::into),
+            event: event.into(),
+            data,
+        })
+    }
+}
+
+/// Convert an event stream chunk into WebSocket messages.
+///
+/// With a binary encoding, event data is decoded from JSON, or sent as a
+/// string if it is not JSON.
+pub(crate) fn event_stream_chunk_to_websocket(
+    chunk: &[u8],
+    encoding: Encoding,
+) -> Vec<hyper::body::Bytes> {
+    parse_event_stream_chunk(chunk)
+        .into_iter()
+        .map(|event| {
+            if encoding.is_binary() {
+                let data = serde_json::from_str(&event.data)
+                    .unwrap_or(serde_json::Value::String(event.data));
+                encoding.encode(&BinaryWebSocketEvent {
+                    id: event.id,
+                    event: event.event,
+                    data,
+                })
+            } else {
+                Encoding::Json.encode(&event)
+            }
+            .expect("encode")
+            .into()
+        })
+        .collect()
+}
+
+/// Parse an event stream chunk into the events it contains.
+///
+/// Chunks sent to an [EventChunkSender] are formatted as [server sent
+/// events](https://html.spec.whatwg.org/multipage/server-sent-events.html).
+/// Comments and fields other than `id`, `event` and `data` are ignored.
+fn parse_event_stream_chunk(chunk: &[u8]) -> Vec<WebSocketEvent> {
+    let text = String::from_utf8_lossy(chunk);
+    let mut result = Vec::new();
+    for block in text.split("\n\n") {
+        let mut id = None;
+        let mut event = None;
+        let mut data: Option<String> = None;
+        for line in block.lines() {
+            let (field, value) = match line.find(':') {
+                Some(0) => continue, // comment
+                Some(idx) => {
+                    let value = &line[idx + 1..];
+                    // Only a single leading space is removed.
+                    (&line[..idx], value.strip_prefix(' ').unwrap_or(value))
+                }
+                None => (line, ""),
+            };
+            match field {
+                "id" => id = Some(value.to_string()),
+                "event" => event = Some(value.to_string()),
+                "data" => match data {
+                    Some(ref mut data) => {
+                        data.push('\n');
+                        data.push_str(value);
+                    }
+                    None => data = Some(value.to_string()),
+                },
+                _ => {}
+            }
+        }
+        if let Some(data) = data {
+            let event = event.unwrap_or_else(|| "message".to_string());
+            result.push(WebSocketEvent { id, event, data });
+        }
+    }
+    result
+}
+
+fn handle_callback<CB>(
+    handler: Box<dyn DynCallbackHandler<Data = CB>>,
+    session_key: bui_backend_types::SessionKey,
+    auth: SessionAuth,
+    resp0: http::response::Builder,
+    req: http::Request<RequestBody>,
+) -> Pin<Box<dyn Future<Output = http::Response<MyBody>> + Send>>
+where
+    CB: 'static + serde::de::DeserializeOwned + Send,
+{
+    // Decode according to the content type, defaulting to JSON.
+    let encoding = match req.headers().get(hyper::header::CONTENT_TYPE) {
+        None => Encoding::Json,
+        Some(content_type) => match content_type
+            .to_str()
+            .ok()
+            .and_then(Encoding::from_content_type)
+        {
+            Some(encoding) => encoding,
+            None => {
+                let estr = format!("Unsupported callback content type: {:?}", content_type);
+                warn!("{}", estr);
+                let e = ErrorsBackToBrowser::new(estr);
+                let body_buf = serde_json::to_vec(&e).unwrap();
+                let resp = resp0
+                    .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
+                    .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                    .body(body_from_buf(&body_buf))
+                    .expect("response");
+                return Box::pin(futures::future::ready(resp));
+            }
+        },
+    };
+
+    let result = async move {
+        let body = req.into_body();
+        let chunks = {
             use http_body_util::BodyExt;
             body.collect().await
         };
-        let data = chunks?.to_bytes();
+        let data = match chunks {
+            Ok(chunks) => chunks.to_bytes(),
+            Err(e) => {
+                let estr = format!("Failed reading callback body: {}", e);
+                warn!("{}", estr);
+                let e = ErrorsBackToBrowser::new(estr);
+                let body_buf = serde_json::to_vec(&e).unwrap();
+                return resp0
+                    .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(body_from_buf(&body_buf))
+                    .expect("response");
+            }
+        };
 
         // parse data
 
-        // Here we convert from a Vec<u8> JSON buf to our
-        // generic type `CB` whose definition can be shared
+        // Here we convert from a Vec<u8> JSON (or other encoding) buf to
+        // our generic type `CB` whose definition can be shared
         // between backend and frontend if using a Rust frontend.
         // (If not using a rust frontend, the payload should be
         // constructed such that this conversion succeeds.
-        match serde_json::from_slice::<CB>(&data) {
+        match encoding.decode::<CB>(&data) {
             Ok(payload) => {
-                let args2 = CallbackDataAndSession {
-                    payload,
-                    session_key,
-                };
+                let args2 = auth.callback_data(payload, session_key);
 
                 let x = {
-                    let fut = handler.call(args2);
+                    let fut = handler.call_dyn(args2, ReplyFormat::Http(encoding));
                     fut.await
                 };
 
                 // Send the payload to callback.
-                let r0 = match x {
-                    Ok(()) => resp0
+                match x {
+                    Ok(None) => resp0
                         .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
                         .body(body_from_buf(JSON_NULL))
                         .expect("response"),
+                    Ok(Some(body_buf)) => resp0
+                        .header(hyper::header::CONTENT_TYPE, encoding.content_type())
+                        .body(body_from_buf(&body_buf))
+                        .expect("response"),
                     Err(e) => {
-                        error!("internal server error: {:?}", e);
+                        let (status, e) = callback_error_response(e);
+                        let body_buf = serde_json::to_vec(&e).unwrap();
                         resp0
                             .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(body_from_buf(JSON_NULL))
+                            .status(status)
+                            .body(body_from_buf(&body_buf))
                             .expect("response")
                     }
-                };
-                Ok(r0)
+                }
             }
-            Err(e) => Ok(on_json_parse_err(e)),
+            Err(e) => on_parse_err(encoding, e),
         }
     };
     Box::pin(result)
 }
 
-fn on_json_parse_err(e: serde_json::Error) -> http::Response<MyBody> {
-    let estr = format!("Failed parsing JSON: {}", e);
+fn parse_err_msg(encoding: Encoding, e: &EncodingError) -> String {
+    let name = match encoding {
+        Encoding::Json => "JSON",
+        #[cfg(feature = "msgpack")]
+        Encoding::MessagePack => "MessagePack",
+        #[cfg(feature = "cbor")]
+        Encoding::Cbor => "CBOR",
+        _ => encoding.content_type(),
+    };
+    format!("Failed parsing {}: {}", name, e)
+}
+
+fn on_parse_err(encoding: Encoding, e: EncodingError) -> http::Response<MyBody> {
+    let estr = parse_err_msg(encoding, &e);
     warn!("{}", estr);
-    let e = ErrorsBackToBrowser { errors: vec![estr] };
+    let e = ErrorsBackToBrowser::new(estr);
     let body_buf = serde_json::to_vec(&e).unwrap();
     http::Response::builder()
         .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
@@ -455,6 +1822,36 @@ fn on_json_parse_err(e: serde_json::Error) -> http::Response<MyBody> {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct ErrorsBackToBrowser {
     errors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
+}
+
+impl ErrorsBackToBrowser {
+    fn new(estr: String) -> Self {
+        Self {
+            errors: vec![estr],
+            details: None,
+        }
+    }
+}
+
+/// Convert an error from a callback handler into the response to the client.
+fn callback_error_response(e: Box<dyn StdError + Send>) -> (StatusCode, ErrorsBackToBrowser) {
+    match e.downcast_ref::<CallbackError>() {
+        Some(e) => {
+            info!("callback error: {:?}", e);
+            let body = ErrorsBackToBrowser {
+                errors: vec![e.message.clone()],
+                details: e.details.clone(),
+            };
+            (e.status, body)
+        }
+        None => {
+            error!("internal server error: {:?}", e);
+            let body = ErrorsBackToBrowser::new("internal server error".to_string());
+            (StatusCode::INTERNAL_SERVER_ERROR, body)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -471,30 +1868,77 @@ impl std::fmt::Display for ErrorCallbackError {
 /// with cookie which includes the session key.
 #[derive(Debug)]
 enum ValidLogin {
-    ExistingSession(SessionKey),
-    NeedsSessionKey,
+    ExistingSession {
+        key: SessionKey,
+        /// When the session cookie expires.
+        exp: usize,
+        auth: SessionAuth,
+        /// The cookie was signed with a replaced JWT secret.
+        stale_key: bool,
+    },
+    NeedsSessionKey {
+        auth: SessionAuth,
+    },
 }
 
-fn get_session_key<'a>(
+/// Why a request could not be associated with a session.
+#[derive(Debug)]
+enum InvalidLogin {
+    /// The session cookie has expired.
+    Expired,
+    /// The session was revoked or logged out.
+    Revoked,
+    /// No valid token or session cookie was given.
+    Invalid(ErrorsBackToBrowser),
+}
+
+/// Respond to a request from a client whose session has expired or was
+/// revoked.
+fn session_ended_response(msg: &str) -> http::Response<MyBody> {
+    let errors = ErrorsBackToBrowser::new(msg.to_string());
+    let body_buf = serde_json::to_vec(&errors).unwrap();
+    http::Response::builder()
+        .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
+        .status(StatusCode::UNAUTHORIZED)
+        .body(body_from_buf(&body_buf))
+        .expect("response")
+}
+
+async fn get_session_key(
     map: &hyper::HeaderMap<hyper::header::HeaderValue>,
-    query_pairs: url::form_urlencoded::Parse,
+    query_pairs: url::form_urlencoded::Parse<'_>,
     cookie_name: &str,
-    decoding_key: &jsonwebtoken::DecodingKey,
-    valid_token: &AccessToken,
-) -> Result<ValidLogin, ErrorsBackToBrowser> {
+    decoding_keys: &[jsonwebtoken::DecodingKey],
+    authenticator: Option<Arc<dyn Authenticator>>,
+    is_revoked: impl Fn(&SessionKey) -> bool,
+) -> Result<ValidLogin, InvalidLogin> {
     use std::borrow::Cow;
 
     let mut errors = Vec::new();
+    let mut expired = false;
+    let mut revoked = false;
 
     // first check for token in URI
     for (key, value) in query_pairs {
         debug!("got query pair {}, {}", key, value);
         if key == Cow::Borrowed("token") {
-            if valid_token.does_match(&value) {
-                return Ok(ValidLogin::NeedsSessionKey);
-            } else {
-                warn!("incorrect token in URI: {}", value);
-                errors.push("incorrect token in URI".to_string());
+            let authenticator = match authenticator {
+                Some(ref authenticator) => authenticator.clone(),
+                None => {
+                    return Ok(ValidLogin::NeedsSessionKey {
+                        auth: SessionAuth::default(),
+                    })
+                }
+            };
+            match authenticate_blocking(authenticator, &Credentials::Token(&value)).await {
+                AuthOutcome::Authenticated { identity, role } => {
+                    let auth = SessionAuth { identity, role };
+                    return Ok(ValidLogin::NeedsSessionKey { auth });
+                }
+                AuthOutcome::Rejected => {
+                    warn!("incorrect token in URI: {}", value);
+                    errors.push("incorrect token in URI".to_string());
+                }
             }
         }
     }
@@ -510,16 +1954,25 @@ fn get_session_key<'a>(
                         if c.name() == cookie_name {
                             let encoded = c.value();
                             debug!("jwt_encoded = {}", encoded);
-                            let validation = jsonwebtoken::Validation::new(Default::default());
-                            match jsonwebtoken::decode::<JwtClaims>(
-                                encoded,
-                                decoding_key,
-                                &validation,
-                            )
-                            .map(|token| token.claims.key)
-                            {
-                                Ok(k) => return Ok(ValidLogin::ExistingSession(k)),
+                            match decode_session_cookie(encoded, decoding_keys) {
+                                Ok((claims, _)) if is_revoked(&claims.key) => {
+                                    debug!("session {:?} was revoked", claims.key);
+                                    revoked = true;
+                                }
+                                Ok((claims, stale_key)) => {
+                                    return Ok(ValidLogin::ExistingSession {
+                                        key: claims.key,
+                                        exp: claims.exp,
+                                        auth: claims.auth,
+                                        stale_key,
+                                    })
+                                }
                                 Err(e) => {
+                                    if let jsonwebtoken::errors::ErrorKind::ExpiredSignature =
+                                        e.kind()
+                                    {
+                                        expired = true;
+                                    }
                                     warn!("client passed token in cookie {:?}, resulting in error: {:?}", c, e);
                                     let estr = format!("{}: {:?}", e, e);
                                     errors.push(estr);
@@ -542,55 +1995,390 @@ fn get_session_key<'a>(
         }
     }
 
+    // if no session cookie either, check for HTTP Basic credentials.
+    if let Some(ref authenticator) = authenticator {
+        if let Some((username, password)) = get_basic_credentials(map) {
+            let credentials = Credentials::Password {
+                username: &username,
+                password: &password,
+            };
+            match authenticate_blocking(authenticator.clone(), &credentials).await {
+                AuthOutcome::Authenticated { identity, role } => {
+                    let auth = SessionAuth { identity, role };
+                    return Ok(ValidLogin::NeedsSessionKey { auth });
+                }
+                AuthOutcome::Rejected => {
+                    warn!("incorrect credentials for user {:?}", username);
+                    errors.push("incorrect user name or password".to_string());
+                }
+            }
+        }
+    }
+
     // If we are here, we got no (valid) session key.
     debug!("no (valid) session key found");
-    match valid_token {
-        AccessToken::NoToken => {
+    match authenticator {
+        None => {
             debug!("no token needed, will give new session key");
-            Ok(ValidLogin::NeedsSessionKey)
+            Ok(ValidLogin::NeedsSessionKey {
+                auth: SessionAuth::default(),
+            })
         }
-        _ => {
+        Some(_) if revoked => Err(InvalidLogin::Revoked),
+        Some(_) if expired => Err(InvalidLogin::Expired),
+        Some(_) => {
             errors.push("no valid session key".to_string());
-            Err(ErrorsBackToBrowser { errors })
+            Err(InvalidLogin::Invalid(ErrorsBackToBrowser {
+                errors,
+                details: None,
+            }))
         }
     }
 }
 
-impl<CB> hyper::service::Service<hyper::Request<hyper::body::Incoming>> for BuiService<CB>
+/// Start a session for the user name and password posted to `/login`.
+///
+/// On success, the client is redirected to the base path. After repeated
+/// failures, logins from the same address are refused with status 429 for a
+/// while (see [LoginThrottle]).
+async fn handle_login<CB>(
+    self_: BuiService<CB>,
+    req: http::Request<RequestBody>,
+) -> http::Response<MyBody>
 where
     CB: 'static + serde::de::DeserializeOwned + Clone + Send,
 {
-    type Response = hyper::Response<MyBody>;
-    type Error = hyper::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    let authenticator = match self_.access.authenticator() {
+        Some(authenticator) => authenticator,
+        None => {
+            return http::Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body_from_buf(&[]))
+                .expect("response");
+        }
+    };
+    let remote_addr = req.extensions().get::<Address>().cloned();
+    let retry_after = self_
+        .login_throttle
+        .lock()
+        .retry_after(remote_addr.as_ref(), std::time::Instant::now());
+    if let Some(retry_after) = retry_after {
+        warn!(
+            "refusing login from {:?} after repeated failures",
+            remote_addr
+        );
+        const MSG: &str = "too many failed logins, try again later";
+        let resp = if authenticator.login_form() {
+            self_.login_form_response(Some(MSG))
+        } else {
+            let errors = ErrorsBackToBrowser::new(MSG.to_string());
+            let body_buf = serde_json::to_vec(&errors).unwrap();
+            http::Response::builder()
+                .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
+                .body(body_from_buf(&body_buf))
+                .expect("response")
+        };
+        let (mut parts, body) = resp.into_parts();
+        parts.status = StatusCode::TOO_MANY_REQUESTS;
+        // Round up, such that the client does not retry too early.
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        parts
+            .headers
+            .insert(hyper::header::RETRY_AFTER, secs.into());
+        return http::Response::from_parts(parts, body);
+    }
+    let body = {
+        use http_body_util::BodyExt;
+        req.into_body().collect().await
+    };
+    let body = match body {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            let e = ErrorsBackToBrowser::new(format!("Failed reading login body: {}", e));
+            let body_buf = serde_json::to_vec(&e).unwrap();
+            return http::Response::builder()
+                .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
+                .status(StatusCode::BAD_REQUEST)
+                .body(body_from_buf(&body_buf))
+                .expect("response");
+        }
+    };
+    let mut username = None;
+    let mut password = None;
+    for (key, value) in url::form_urlencoded::parse(&body) {
+        match key.as_ref() {
+            "username" => username = Some(value.into_owned()),
+            "password" => password = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    let outcome = match (&username, &password) {
+        (Some(username), Some(password)) => {
+            let throttled = Throttled {
+                inner: authenticator.clone(),
+                throttle: self_.login_throttle.clone(),
+                remote_addr: remote_addr.clone(),
+            };
+            let credentials = Credentials::Password { username, password };
+            authenticate_blocking(Arc::new(throttled), &credentials).await
+        }
+        _ => AuthOutcome::Rejected,
+    };
+    match outcome {
+        AuthOutcome::Authenticated { identity, role } => {
+            let auth = SessionAuth { identity, role };
+            let login_info = ValidLogin::NeedsSessionKey { auth };
+            let (resp, session_key, _) =
+                self_.start_session(login_info, http::Response::builder(), remote_addr);
+            debug!("logged in session {:?} of user {:?}", session_key, username);
+            resp.status(StatusCode::SEE_OTHER)
+                .header(hyper::header::LOCATION, format!("{}/", self_.base_path))
+                .body(body_from_buf(&[]))
+                .expect("response")
+        }
+        AuthOutcome::Rejected => {
+            warn!("incorrect login for user {:?}", username);
+            const MSG: &str = "incorrect user name or password";
+            if authenticator.login_form() {
+                self_.login_form_response(Some(MSG))
+            } else {
+                let errors = ErrorsBackToBrowser::new(MSG.to_string());
+                let body_buf = serde_json::to_vec(&errors).unwrap();
+                http::Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(body_from_buf(&body_buf))
+                    .expect("response")
+            }
+        }
+    }
+}
 
-    fn call(&self, req: http::Request<hyper::body::Incoming>) -> Self::Future {
-        let decoding_key = jsonwebtoken::DecodingKey::from_secret(&self.jwt_secret);
-        // Parse cookies.
-        let res_session_key = {
-            let query = req.uri().query();
-            debug!("parsing query {:?}", query);
-
-            let pairs = url::form_urlencoded::parse(query.unwrap_or("").as_bytes());
-
-            get_session_key(
-                req.headers(),
-                pairs,
-                &self.config.cookie_name,
-                &decoding_key,
-                &self.valid_token,
-            )
+/// Decode a session cookie with the first of `decoding_keys` which accepts it.
+///
+/// Also returns whether the cookie was signed with a key other than the
+/// current (first) one.
+fn decode_session_cookie(
+    encoded: &str,
+    decoding_keys: &[jsonwebtoken::DecodingKey],
+) -> Result<(JwtClaims, bool), jsonwebtoken::errors::Error> {
+    let mut validation = jsonwebtoken::Validation::new(Default::default());
+    // The tokens are issued by this server, so there
+    // is no clock skew to allow for.
+    validation.leeway = 0;
+    let mut first_err = None;
+    for (i, decoding_key) in decoding_keys.iter().enumerate() {
+        match jsonwebtoken::decode::<JwtClaims>(encoded, decoding_key, &validation) {
+            Ok(token) => return Ok((token.claims, i > 0)),
+            // The signature matched, so other keys need not be tried.
+            Err(e) if *e.kind() == jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
+                return Err(e)
+            }
+            Err(e) => {
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_err.unwrap_or_else(|| jsonwebtoken::errors::ErrorKind::InvalidSignature.into()))
+}
+
+/// Get the user name and password of an HTTP Basic `Authorization` header.
+fn get_basic_credentials(
+    map: &hyper::HeaderMap<hyper::header::HeaderValue>,
+) -> Option<(String, String)> {
+    use base64::Engine;
+
+    let value = map.get(hyper::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Remove `base_path` from the path of `req`.
+///
+/// Returns the response to send instead if the request is not for a path
+/// below the base path.
+#[allow(clippy::result_large_err)]
+fn strip_base_path(
+    base_path: &str,
+    mut req: http::Request<RequestBody>,
+) -> Result<http::Request<RequestBody>, http::Response<MyBody>> {
+    if base_path.is_empty() {
+        return Ok(req);
+    }
+    let path = match req.uri().path().strip_prefix(base_path) {
+        Some("") => {
+            // Redirect to the base path with a trailing slash, so that
+            // relative URLs used by the frontend resolve below it.
+            let mut location = format!("{}/", base_path);
+            if let Some(query) = req.uri().query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            return Err(http::Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(hyper::header::LOCATION, location)
+                .body(body_from_buf(&[]))
+                .expect("response"));
+        }
+        Some(path) if path.starts_with('/') => path,
+        _ => {
+            return Err(http::Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body_from_buf(&[]))
+                .expect("response"));
+        }
+    };
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().expect("path and query"));
+    *req.uri_mut() = http::Uri::from_parts(parts).expect("uri");
+    Ok(req)
+}
+
+type ResponseFuture = Pin<Box<dyn Future<Output = http::Response<MyBody>> + Send>>;
+
+impl<CB> BuiService<CB>
+where
+    CB: 'static + serde::de::DeserializeOwned + Clone + Send,
+{
+    /// Ask a client without a valid session to authenticate, if the
+    /// authenticator asks for HTTP Basic credentials or shows a login form.
+    ///
+    /// Otherwise, `fallback` is returned.
+    fn unauthenticated_response(
+        &self,
+        req: &http::Request<RequestBody>,
+        error: Option<&str>,
+        fallback: http::Response<MyBody>,
+    ) -> http::Response<MyBody> {
+        let authenticator = match self.access.authenticator() {
+            Some(authenticator) => authenticator,
+            None => return fallback,
         };
+        if let Some(realm) = authenticator.basic_realm() {
+            let errors = ErrorsBackToBrowser::new("authentication required".to_string());
+            let body_buf = serde_json::to_vec(&errors).unwrap();
+            let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm);
+            return http::Response::builder()
+                .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
+                .header(hyper::header::WWW_AUTHENTICATE, challenge)
+                .status(StatusCode::UNAUTHORIZED)
+                .body(body_from_buf(&body_buf))
+                .expect("response");
+        }
+        if authenticator.login_form()
+            && req.method() == Method::GET
+            && !req.uri().path().starts_with(&self.events_prefix)
+        {
+            return self.login_form_response(error);
+        }
+        fallback
+    }
 
+    /// Respond with the login form, with status 401.
+    fn login_form_response(&self, error: Option<&str>) -> http::Response<MyBody> {
+        let action = format!("{}/login", self.base_path);
+        let html = crate::auth::login_form_html(&action, error);
+        http::Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body_from_buf(html.as_bytes()))
+            .expect("response")
+    }
+
+    /// Respond to a request, whichever server it came from.
+    fn serve(&self, req: http::Request<RequestBody>) -> ResponseFuture {
+        let req = match strip_base_path(&self.base_path, req) {
+            Ok(req) => req,
+            Err(resp) => return Box::pin(std::future::ready(resp)),
+        };
+        let decoding_keys = self.access.decoding_keys();
+        let authenticator = self.access.authenticator().map(|inner| {
+            Arc::new(Throttled {
+                inner,
+                throttle: self.login_throttle.clone(),
+                remote_addr: req.extensions().get::<Address>().cloned(),
+            }) as Arc<dyn Authenticator>
+        });
+        let self_ = self.clone();
+        Box::pin(async move {
+            // Parse cookies.
+            let res_session_key = {
+                let query = req.uri().query();
+                debug!("parsing query {:?}", query);
+
+                let pairs = url::form_urlencoded::parse(query.unwrap_or("").as_bytes());
+                get_session_key(
+                    req.headers(),
+                    pairs,
+                    &self_.config.cookie_name,
+                    &decoding_keys,
+                    authenticator,
+                    |key| self_.sessions.lock().is_revoked(key),
+                )
+                .await
+            };
+            self_.respond(req, res_session_key).await
+        })
+    }
+
+    /// Respond to a request of the session `res_session_key`.
+    fn respond(
+        &self,
+        req: http::Request<RequestBody>,
+        res_session_key: Result<ValidLogin, InvalidLogin>,
+    ) -> ResponseFuture {
         debug!(
             "got request from session key {:?}: {:?}",
             res_session_key, req
         );
 
+        if req.method() == Method::POST && req.uri().path() == "/logout" {
+            if let Ok(ValidLogin::ExistingSession { key, .. }) = res_session_key {
+                debug!("logging out session {:?}", key);
+                self.revoke_session(key);
+            }
+            let resp = http::Response::builder()
+                .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
+                .header(
+                    hyper::header::SET_COOKIE,
+                    hyper::header::HeaderValue::from_str(&self.removal_cookie().to_string())
+                        .unwrap(),
+                )
+                .body(body_from_buf(b"{}"))
+                .expect("response");
+            return Box::pin(std::future::ready(resp));
+        }
+
+        if req.method() == Method::POST && req.uri().path() == "/login" {
+            return Box::pin(handle_login(self.clone(), req));
+        }
+
         if req.method() == Method::POST && req.uri().path() == "/callback" {
             let login_info = match res_session_key {
                 Ok(login_info) => login_info,
-                Err(errors) => {
+                Err(InvalidLogin::Expired) => {
+                    return Box::pin(std::future::ready(session_ended_response(
+                        SESSION_EXPIRED_MSG,
+                    )));
+                }
+                Err(InvalidLogin::Revoked) => {
+                    return Box::pin(std::future::ready(session_ended_response(
+                        SESSION_REVOKED_MSG,
+                    )));
+                }
+                Err(InvalidLogin::Invalid(errors)) => {
                     warn!("no (valid) session key in callback");
                     let body_buf = serde_json::to_vec(&errors).unwrap();
                     let resp = http::Response::builder()
@@ -598,23 +2386,18 @@ where
                         .status(StatusCode::BAD_REQUEST)
                         .body(body_from_buf(&body_buf))
                         .expect("response");
-                    return Box::pin(std::future::ready(Ok(resp)));
+                    return Box::pin(std::future::ready(resp));
                 }
             };
 
-            let mut resp0 = http::Response::builder();
-            let session_key = match login_info {
-                ValidLogin::NeedsSessionKey => {
-                    let (resp2, session_key) = self.do_set_cookie_x(resp0);
-                    resp0 = resp2;
-                    session_key
-                }
-                ValidLogin::ExistingSession(k) => k,
-            };
+            let remote_addr = req.extensions().get::<Address>().cloned();
+            let (resp0, session_key, auth) =
+                self.start_session(login_info, http::Response::builder(), remote_addr);
 
             return Box::pin(handle_callback(
-                self.callback_handler.clone(),
+                self.callback_handler.get(),
                 session_key,
+                auth,
                 resp0,
                 req,
             ));
@@ -624,9 +2407,25 @@ where
 
         let login_info = match res_session_key {
             Ok(login_info) => login_info,
-            Err(_errors) => {
+            Err(InvalidLogin::Expired) => {
+                let resp = self.unauthenticated_response(
+                    &req,
+                    Some("session expired, log in again"),
+                    session_ended_response(SESSION_EXPIRED_MSG),
+                );
+                return Box::pin(std::future::ready(resp));
+            }
+            Err(InvalidLogin::Revoked) => {
+                let resp = self.unauthenticated_response(
+                    &req,
+                    None,
+                    session_ended_response(SESSION_REVOKED_MSG),
+                );
+                return Box::pin(std::future::ready(resp));
+            }
+            Err(InvalidLogin::Invalid(_errors)) => {
                 let estr = "No (valid) token in request.".to_string();
-                let errors = ErrorsBackToBrowser { errors: vec![estr] };
+                let errors = ErrorsBackToBrowser::new(estr);
 
                 let body_buf = serde_json::to_vec(&errors).unwrap();
                 let resp = http::Response::builder()
@@ -634,7 +2433,8 @@ where
                     .status(StatusCode::BAD_REQUEST)
                     .body(body_from_buf(&body_buf))
                     .expect("response");
-                return Box::pin(std::future::ready(Ok(resp)));
+                let resp = self.unauthenticated_response(&req, None, resp);
+                return Box::pin(std::future::ready(resp));
             }
         };
 
@@ -647,7 +2447,7 @@ where
             self.raw_req_handler.clone(),
         )
         .map(|r| match r {
-            Ok(x) => Ok(x),
+            Ok(x) => x,
             Err(_e) => unimplemented!(),
         });
 
@@ -655,6 +2455,54 @@ where
     }
 }
 
+impl<CB> hyper::service::Service<hyper::Request<hyper::body::Incoming>> for BuiService<CB>
+where
+    CB: 'static + serde::de::DeserializeOwned + Clone + Send,
+{
+    type Response = hyper::Response<MyBody>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: http::Request<hyper::body::Incoming>) -> Self::Future {
+        use futures::future::FutureExt;
+        use http_body_util::BodyExt;
+        let req = req.map(|body| body.map_err(Into::into).boxed_unsync());
+        Box::pin(self.serve(req).map(Ok))
+    }
+}
+
+/// Allows mounting the BUI in another server, such as an
+/// [axum](https://crates.io/crates/axum) application.
+///
+/// Requests to the events path are answered with event streams which are fed
+/// by the `BuiAppInner` created from this service. For WebSocket upgrades to
+/// work, the server must support HTTP upgrades and keep hyper's
+/// `OnUpgrade` request extension.
+impl<CB, B> tower_service::Service<http::Request<B>> for BuiService<CB>
+where
+    CB: 'static + serde::de::DeserializeOwned + Clone + Send,
+    B: http_body::Body<Data = bytes::Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = http::Response<MyBody>;
+    type Error = std::convert::Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        use futures::future::FutureExt;
+        use http_body_util::BodyExt;
+        let req = req.map(|body| body.map_err(Into::into).boxed_unsync());
+        Box::pin(self.serve(req).map(Ok))
+    }
+}
+
 /// Create a stream of connection events and a `BuiService`.
 pub fn launcher<CB>(
     config: Config,
@@ -663,22 +2511,760 @@ pub fn launcher<CB>(
     events_prefix: &str,
     raw_req_handler: Option<RawReqHandler>,
     callback_handler: Box<dyn Send + CallbackHandler<Data = CB>>,
+) -> (mpsc::Receiver<NewEventStreamConnection>, BuiService<CB>)
+where
+    CB: 'static,
+{
+    launch(
+        config,
+        auth,
+        channel_size,
+        events_prefix,
+        raw_req_handler,
+        Box::new(BoxedCallbackHandler(callback_handler)),
+    )
+}
+
+pub(crate) fn launch<CB>(
+    config: Config,
+    auth: &access_control::AccessControl,
+    channel_size: usize,
+    events_prefix: &str,
+    raw_req_handler: Option<RawReqHandler>,
+    callback_handler: Box<dyn DynCallbackHandler<Data = CB>>,
 ) -> (mpsc::Receiver<NewEventStreamConnection>, BuiService<CB>) {
     let next_connection_key = Arc::new(Mutex::new(ConnectionKey(0)));
+    let base_path = config.base_path.trim_matches('/');
+    let base_path = if base_path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", base_path)
+    };
 
     let (tx_new_connection, rx_new_connection) = mpsc::channel(channel_size);
 
     let service = BuiService {
         config,
-        callback_handler,
+        callback_handler: CallbackHandlerCell(Mutex::new(callback_handler)),
+        sessions: Arc::new(Mutex::new(SessionRegistry::default())),
+        login_throttle: Arc::new(Mutex::new(LoginThrottle::default())),
         next_connection_key,
-        jwt_secret: auth.jwt_secret().to_vec(),
-        encoding_key: jsonwebtoken::EncodingKey::from_secret(auth.jwt_secret()),
-        valid_token: auth.token(),
+        access: SharedAccess::new(auth),
         tx_new_connection,
         events_prefix: events_prefix.to_string(),
+        base_path,
         raw_req_handler,
     };
 
     (rx_new_connection, service)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use async_change_tracker::ChangeTracker;
+    use futures::SinkExt;
+    use parking_lot::RwLock;
+
+    #[cfg(feature = "bundle_files")]
+    static NO_FILES: includedir::Files = includedir::Files {
+        files: phf::Map {
+            key: 0,
+            disps: phf::Slice::Static(&[]),
+            entries: phf::Slice::Static(&[]),
+        },
+        passthrough: std::sync::atomic::AtomicBool::new(false),
+    };
+
+    /// A configuration with default settings serving no files.
+    pub(crate) fn test_config() -> Config {
+        Config::new(
+            std::path::Path::new(""),
+            #[cfg(feature = "bundle_files")]
+            &NO_FILES,
+        )
+    }
+
+    /// A callback handler doing nothing.
+    #[derive(Clone)]
+    pub(crate) struct NoopHandler;
+
+    impl CallbackHandler for NoopHandler {
+        type Data = u8;
+
+        #[allow(clippy::type_complexity)]
+        fn call<'a>(
+            &'a self,
+            _: CallbackDataAndSession<u8>,
+        ) -> Pin<Box<dyn Future<Output = Result<(), Box<dyn StdError + Send>>> + Send + 'a>>
+        {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    pub(crate) fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// A request to open an event stream at `uri`.
+    pub(crate) fn event_stream_request(uri: &str) -> http::Request<RequestBody> {
+        let mut req = request(uri);
+        req.headers_mut()
+            .insert(ACCEPT, "text/event-stream".parse().unwrap());
+        req
+    }
+
+    /// The next chunk of data in `body`, if any.
+    pub(crate) async fn next_chunk(body: &mut MyBody) -> Option<bytes::Bytes> {
+        use http_body_util::BodyExt;
+        while let Some(frame) = body.frame().await {
+            if let Ok(data) = frame.expect("frame").into_data() {
+                return Some(data);
+            }
+        }
+        None
+    }
+
+    fn event(id: Option<&str>, event: &str, data: &str) -> WebSocketEvent {
+        WebSocketEvent {
+            id: id.map(Into::into),
+            event: event.into(),
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn parse_event_stream_chunk_fields() {
+        let chunk = b"id: e-1\nevent: state\ndata: {\"a\":1}\n\n";
+        assert_eq!(
+            parse_event_stream_chunk(chunk),
+            vec![event(Some("e-1"), "state", "{\"a\":1}")]
+        );
+    }
+
+    #[test]
+    fn parse_event_stream_chunk_defaults_and_multiline() {
+        // No event name means `message`; several data lines are joined with
+        // newlines; only one leading space is removed from values.
+        let chunk = b"data: first\ndata:second\ndata:  third\n\n";
+        assert_eq!(
+            parse_event_stream_chunk(chunk),
+            vec![event(None, "message", "first\nsecond\n third")]
+        );
+    }
+
+    #[test]
+    fn parse_event_stream_chunk_several_events() {
+        let chunk = b"retry: 1000\n\nid: 1\ndata: a\n\n: comment\nevent: x\ndata: b\n\n";
+        assert_eq!(
+            parse_event_stream_chunk(chunk),
+            vec![event(Some("1"), "message", "a"), event(None, "x", "b")]
+        );
+    }
+
+    #[test]
+    fn parse_event_stream_chunk_without_events() {
+        // Heartbeats and retry hints carry no data.
+        assert!(parse_event_stream_chunk(b":\n\n").is_empty());
+        assert!(parse_event_stream_chunk(b"retry: 500\n\n").is_empty());
+        assert!(parse_event_stream_chunk(b"").is_empty());
+    }
+
+    #[derive(Serialize)]
+    struct State {
+        b: u8,
+        a: Vec<&'static str>,
+    }
+
+    #[test]
+    fn websocket_event_json() {
+        let state = State { b: 1, a: vec!["x"] };
+        let msg = websocket_event(Encoding::Json, Some("e-1"), "state", &state).unwrap();
+        let decoded: WebSocketEvent = Encoding::Json.decode(&msg).unwrap();
+        assert_eq!(
+            decoded,
+            event(Some("e-1"), "state", "{\"b\":1,\"a\":[\"x\"]}")
+        );
+    }
+
+    #[test]
+    fn event_stream_chunk_to_websocket_json() {
+        let chunk = b"id: e-1\nevent: state\ndata: {\"a\":1}\n\n:\n\n";
+        let messages = event_stream_chunk_to_websocket(chunk, Encoding::Json);
+        assert_eq!(messages.len(), 1);
+        let decoded: WebSocketEvent = Encoding::Json.decode(&messages[0]).unwrap();
+        assert_eq!(decoded, event(Some("e-1"), "state", "{\"a\":1}"));
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn websocket_event_binary_matches_event_stream() {
+        // Encoding the state directly gives the same event as converting
+        // the event stream chunk sent to other clients.
+        let state = State { b: 1, a: vec!["x"] };
+        let encoding = Encoding::MessagePack;
+        let direct = websocket_event(encoding, Some("e-1"), "state", &state).unwrap();
+        let chunk = format!(
+            "id: e-1\nevent: state\ndata: {}\n\n",
+            serde_json::to_string(&state).unwrap()
+        );
+        let converted = event_stream_chunk_to_websocket(chunk.as_bytes(), encoding);
+        assert_eq!(converted.len(), 1);
+        let direct: BinaryWebSocketEvent<serde_json::Value> = encoding.decode(&direct).unwrap();
+        let converted: BinaryWebSocketEvent<serde_json::Value> =
+            encoding.decode(&converted[0]).unwrap();
+        assert_eq!(direct, converted);
+        assert_eq!(direct.data, serde_json::json!({"a": ["x"], "b": 1}));
+
+        // Data which is not JSON is sent as a string.
+        let converted = event_stream_chunk_to_websocket(b"data: hello\n\n", encoding);
+        let converted: BinaryWebSocketEvent<serde_json::Value> =
+            encoding.decode(&converted[0]).unwrap();
+        assert_eq!(converted.data, serde_json::json!("hello"));
+    }
+
+    /// A POST request to `uri` with a JSON body.
+    pub(crate) fn post(uri: &str, body: &str) -> http::Request<RequestBody> {
+        use http_body_util::BodyExt;
+        let body = http_body_util::Full::new(bytes::Bytes::from(body.to_string()))
+            .map_err(|never| match never {})
+            .boxed_unsync();
+        http::Request::post(uri)
+            .header(hyper::header::CONTENT_TYPE, JSON_TYPE)
+            .body(body)
+            .unwrap()
+    }
+
+    /// Respond to `req` with `service`, returning the status and the body.
+    pub(crate) async fn respond<CB>(
+        service: &BuiService<CB>,
+        req: http::Request<RequestBody>,
+    ) -> (StatusCode, String)
+    where
+        CB: 'static + serde::de::DeserializeOwned + Clone + Send,
+    {
+        use http_body_util::BodyExt;
+        let resp = service.serve(req).await;
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    pub(crate) type TestWebSocket = WebSocketStream<tokio::net::TcpStream>;
+
+    /// Open a WebSocket to `path` of the server listening at `addr`.
+    pub(crate) async fn connect_websocket(
+        addr: &std::net::SocketAddr,
+        path: &str,
+    ) -> TestWebSocket {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let url = format!("ws://{}{}", addr, path);
+        let (ws, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+        ws
+    }
+
+    /// The next event received on `ws`, skipping pings.
+    pub(crate) async fn next_websocket_event(ws: &mut TestWebSocket) -> Option<WebSocketEvent> {
+        while let Some(msg) = ws.next().await {
+            match msg {
+                Ok(Message::Text(buf)) => return Some(serde_json::from_str(&buf).unwrap()),
+                Ok(Message::Close(_)) | Err(_) => return None,
+                Ok(_) => continue,
+            }
+        }
+        None
+    }
+
+    /// Answers callbacks with twice their payload.
+    #[derive(Clone)]
+    struct Doubler;
+
+    impl CallbackHandlerWithResponse for Doubler {
+        type Data = u8;
+        type Response = u8;
+
+        #[allow(clippy::type_complexity)]
+        fn call<'a>(
+            &'a self,
+            data_sess: CallbackDataAndSession<u8>,
+        ) -> Pin<Box<dyn Future<Output = Result<u8, Box<dyn StdError + Send>>> + Send + 'a>>
+        {
+            Box::pin(async move { Ok(data_sess.payload.wrapping_mul(2)) })
+        }
+    }
+
+    /// Serve [Doubler] to sessions started with the token `viewer` or
+    /// `controller`.
+    async fn doubler_app(config: Config) -> crate::BuiApp<u8, u8> {
+        let authenticator = crate::auth::TokenAuthenticator::new()
+            .with_token("viewer", Role::Viewer)
+            .with_token("controller", Role::Controller);
+        let auth = crate::highlevel::generate_auth_with_authenticator(
+            "127.0.0.1:0".parse().unwrap(),
+            b"secret".to_vec(),
+            authenticator,
+        );
+        crate::BuiApp::builder(config, Arc::new(RwLock::new(ChangeTracker::new(0))))
+            .auth(auth)
+            .callback_handler(Doubler)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[derive(Clone)]
+    struct EchoHandler;
+
+    impl CallbackHandlerWithResponse for EchoHandler {
+        type Data = u8;
+        type Response = std::collections::BTreeMap<(u8, u8), u8>;
+
+        #[allow(clippy::type_complexity)]
+        fn call<'a>(
+            &'a self,
+            data_sess: CallbackDataAndSession<u8>,
+        ) -> Pin<
+            Box<dyn Future<Output = Result<Self::Response, Box<dyn StdError + Send>>> + Send + 'a>,
+        > {
+            let n = data_sess.payload;
+            Box::pin(async move { Ok(Some(((n, n), n)).into_iter().filter(|_| n > 0).collect()) })
+        }
+    }
+
+    fn call<H: DynCallbackHandler<Data = u8>>(
+        handler: &H,
+        payload: u8,
+        reply: ReplyFormat,
+    ) -> Result<Option<Vec<u8>>, Box<dyn StdError + Send>> {
+        let data_sess = CallbackDataAndSession::new(payload, SessionKey::new());
+        futures::executor::block_on(handler.call_dyn(data_sess, reply))
+    }
+
+    #[test]
+    fn callback_responses() {
+        let http = ReplyFormat::Http(Encoding::Json);
+        let ws = ReplyFormat::WebSocket(Encoding::Json);
+
+        // Handlers without a response send none.
+        #[derive(Clone)]
+        struct Unit;
+        impl CallbackHandler for Unit {
+            type Data = u8;
+            fn call<'a>(
+                &'a self,
+                _: CallbackDataAndSession<u8>,
+            ) -> Pin<Box<dyn Future<Output = Result<(), Box<dyn StdError + Send>>> + Send + 'a>>
+            {
+                Box::pin(async { Ok(()) })
+            }
+        }
+        assert_eq!(call(&Unit, 1, http).unwrap(), None);
+        assert_eq!(call(&Unit, 1, ws).unwrap(), None);
+
+        let buf = call(&EchoHandler, 0, http).unwrap().unwrap();
+        assert_eq!(buf, b"{}");
+        let buf = call(&EchoHandler, 0, ws).unwrap().unwrap();
+        let reply: WebSocketEvent = Encoding::Json.decode(&buf).unwrap();
+        assert_eq!(reply, event(None, CALLBACK_REPLY_EVENT, "{}"));
+
+        // Responses encoded as `null` are sent, too.
+        #[derive(Clone)]
+        struct Maybe;
+        impl CallbackHandlerWithResponse for Maybe {
+            type Data = u8;
+            type Response = Option<u8>;
+            #[allow(clippy::type_complexity)]
+            fn call<'a>(
+                &'a self,
+                data_sess: CallbackDataAndSession<u8>,
+            ) -> Pin<
+                Box<dyn Future<Output = Result<Option<u8>, Box<dyn StdError + Send>>> + Send + 'a>,
+            > {
+                let n = data_sess.payload;
+                Box::pin(async move { Ok(Some(n).filter(|n| *n > 0)) })
+            }
+        }
+        assert_eq!(call(&Maybe, 0, http).unwrap().unwrap(), b"null");
+        assert_eq!(call(&Maybe, 1, http).unwrap().unwrap(), b"1");
+        let buf = call(&Maybe, 0, ws).unwrap().unwrap();
+        let reply: WebSocketEvent = Encoding::Json.decode(&buf).unwrap();
+        assert_eq!(reply, event(None, CALLBACK_REPLY_EVENT, "null"));
+    }
+
+    #[test]
+    fn callback_authorization_http() {
+        runtime().block_on(async {
+            let app = doubler_app(test_config()).await;
+            let service = app.inner.bui_service();
+            let (status, body) = respond(service, post("/callback?token=viewer", "2")).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(
+                body,
+                r#"{"errors":["not authorized to send this callback"]}"#
+            );
+            let (status, body) = respond(service, post("/callback?token=controller", "2")).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, "4");
+            app.inner.shutdown().await;
+        });
+    }
+
+    /// Accepts the token `t` and the user `u` with password `p`, recording the
+    /// threads on which credentials are checked.
+    #[derive(Debug, Clone, Default)]
+    struct ThreadRecorder(Arc<Mutex<Vec<std::thread::ThreadId>>>);
+
+    impl Authenticator for ThreadRecorder {
+        fn authenticate(&self, credentials: &Credentials<'_>) -> AuthOutcome {
+            self.0.lock().push(std::thread::current().id());
+            match credentials {
+                Credentials::Token("t")
+                | Credentials::Password {
+                    username: "u",
+                    password: "p",
+                } => AuthOutcome::Authenticated {
+                    identity: None,
+                    role: Role::Controller,
+                },
+                _ => AuthOutcome::Rejected,
+            }
+        }
+    }
+
+    #[test]
+    fn authenticate_off_runtime_thread() {
+        runtime().block_on(async {
+            let recorder = ThreadRecorder::default();
+            let auth = crate::highlevel::generate_auth_with_authenticator(
+                "127.0.0.1:0".parse().unwrap(),
+                b"secret".to_vec(),
+                recorder.clone(),
+            );
+            let app: crate::BuiApp<u8, u8> =
+                crate::BuiApp::builder(test_config(), Arc::new(RwLock::new(ChangeTracker::new(0))))
+                    .auth(auth)
+                    .callback_handler(NoopHandler)
+                    .build()
+                    .await
+                    .unwrap();
+            let service = app.inner.bui_service();
+
+            let (status, _) = respond(service, post("/callback?token=t", "2")).await;
+            assert_eq!(status, StatusCode::OK);
+            let mut req = post("/callback", "2");
+            req.headers_mut().insert(
+                hyper::header::AUTHORIZATION,
+                "Basic dTpw".parse().unwrap(), // u:p
+            );
+            let (status, _) = respond(service, req).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = respond(service, post("/login", "username=u&password=p")).await;
+            assert_eq!(status, StatusCode::SEE_OTHER);
+
+            // The runtime runs on this thread, which is not blocked.
+            let threads = recorder.0.lock().clone();
+            assert_eq!(threads.len(), 3);
+            assert!(threads.iter().all(|t| *t != std::thread::current().id()));
+            app.inner.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn callback_authorization_websocket() {
+        runtime().block_on(async {
+            let app = doubler_app(test_config()).await;
+            let addr = *app.inner.local_addr();
+
+            let mut ws = connect_websocket(&addr, "/events?token=viewer").await;
+            // The initial state.
+            let state = next_websocket_event(&mut ws).await.unwrap();
+            assert_eq!(state.data, "0");
+            ws.send(Message::text("2")).await.unwrap();
+            let reply = next_websocket_event(&mut ws).await.unwrap();
+            assert_eq!(reply.event, CALLBACK_ERROR_EVENT);
+            assert_eq!(
+                reply.data,
+                r#"{"errors":["not authorized to send this callback"]}"#
+            );
+
+            let mut ws = connect_websocket(&addr, "/events?token=controller").await;
+            next_websocket_event(&mut ws).await.unwrap();
+            ws.send(Message::text("2")).await.unwrap();
+            let reply = next_websocket_event(&mut ws).await.unwrap();
+            assert_eq!(reply, event(None, CALLBACK_REPLY_EVENT, "4"));
+            app.inner.shutdown().await;
+        });
+    }
+
+    /// Wait until shortly after the start of the next second, so that the
+    /// timing of tests is not affected by the rounding of cookie expiry.
+    async fn next_second() {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        let rest = std::time::Duration::from_secs(1)
+            - std::time::Duration::from_nanos(now.subsec_nanos().into());
+        tokio::time::sleep(rest + std::time::Duration::from_millis(100)).await;
+    }
+
+    #[test]
+    fn session_expiry_closes_event_streams() {
+        runtime().block_on(async {
+            let mut config = test_config();
+            config.session_lifetime = Some(std::time::Duration::from_secs(2));
+            let app = doubler_app(config).await;
+            let service = app.inner.bui_service();
+            let second = std::time::Duration::from_secs(1);
+
+            next_second().await;
+            let resp = service
+                .serve(event_stream_request("/events?token=controller"))
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let set_cookie = resp.headers()[hyper::header::SET_COOKIE].to_str().unwrap();
+            let cookie = set_cookie.split(';').next().unwrap().to_string();
+            let mut body = resp.into_body();
+            next_chunk(&mut body).await.unwrap();
+
+            // A request in the last second of the session refreshes the
+            // cookie, which keeps the event stream open.
+            tokio::time::sleep(2 * second).await;
+            let mut req = post("/callback", "2");
+            req.headers_mut()
+                .insert(hyper::header::COOKIE, cookie.parse().unwrap());
+            let resp = service.serve(req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(resp.headers().contains_key(hyper::header::SET_COOKIE));
+            tokio::time::sleep(second + second / 5).await;
+            app.inner.shared_arc().write().modify(|x| *x = 1);
+            let chunk = next_chunk(&mut body).await.unwrap();
+            assert!(String::from_utf8_lossy(&chunk).contains("data: 1\n"));
+
+            // Without further requests, the session expires.
+            let end = tokio::time::timeout(3 * second, next_chunk(&mut body)).await;
+            assert_eq!(end, Ok(None));
+            app.inner.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn session_expiry_closes_websockets() {
+        runtime().block_on(async {
+            let mut config = test_config();
+            config.session_lifetime = Some(std::time::Duration::from_secs(1));
+            let app = doubler_app(config).await;
+            let addr = *app.inner.local_addr();
+
+            let mut ws = connect_websocket(&addr, "/events?token=controller").await;
+            next_websocket_event(&mut ws).await.unwrap();
+            ws.send(Message::text("2")).await.unwrap();
+            let reply = next_websocket_event(&mut ws).await.unwrap();
+            assert_eq!(reply, event(None, CALLBACK_REPLY_EVENT, "4"));
+
+            let second = std::time::Duration::from_secs(1);
+            let end = tokio::time::timeout(3 * second, next_websocket_event(&mut ws)).await;
+            assert_eq!(end, Ok(None));
+            app.inner.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn websocket_callbacks_of_ended_sessions() {
+        let mut sessions = SessionRegistry::default();
+        let now = std::time::SystemTime::now();
+        let minute = std::time::Duration::from_secs(60);
+        let (current, expired, revoked) = (SessionKey::new(), SessionKey::new(), SessionKey::new());
+        sessions.seen(current, None, None, Role::Controller, Some(now + minute));
+        sessions.seen(expired, None, None, Role::Controller, Some(now - minute));
+        sessions.seen(revoked, None, None, Role::Controller, Some(now + minute));
+        sessions.revoke(revoked);
+        assert_eq!(session_ended_msg(&sessions, &current), None);
+        assert_eq!(
+            session_ended_msg(&sessions, &expired),
+            Some(SESSION_EXPIRED_MSG)
+        );
+        assert_eq!(
+            session_ended_msg(&sessions, &revoked),
+            Some(SESSION_REVOKED_MSG)
+        );
+    }
+
+    /// Answers callbacks with twice their payload after a while, recording
+    /// when done.
+    #[derive(Clone, Default)]
+    struct SlowDoubler(Arc<std::sync::atomic::AtomicBool>);
+
+    impl CallbackHandlerWithResponse for SlowDoubler {
+        type Data = u8;
+        type Response = u8;
+
+        #[allow(clippy::type_complexity)]
+        fn call<'a>(
+            &'a self,
+            data_sess: CallbackDataAndSession<u8>,
+        ) -> Pin<
+            Box<dyn Future<Output = Result<Self::Response, Box<dyn StdError + Send>>> + Send + 'a>,
+        > {
+            Box::pin(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+                Ok(data_sess.payload * 2)
+            })
+        }
+    }
+
+    #[test]
+    fn shutdown_completes_websocket_callbacks() {
+        runtime().block_on(async {
+            let handler = SlowDoubler::default();
+            let app: crate::BuiApp<u8, u8> =
+                crate::BuiApp::builder(test_config(), Arc::new(RwLock::new(ChangeTracker::new(0))))
+                    .auth(access_control::AccessControl::Insecure(
+                        "127.0.0.1:0".parse().unwrap(),
+                    ))
+                    .callback_handler(handler.clone())
+                    .build()
+                    .await
+                    .unwrap();
+            let addr = *app.inner.local_addr();
+            let mut ws = connect_websocket(&addr, "/events").await;
+            next_websocket_event(&mut ws).await.unwrap();
+            ws.send(Message::text("2")).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+            // The server stops once the callback in progress is completed.
+            app.inner.shutdown().await;
+            assert!(handler.0.load(std::sync::atomic::Ordering::SeqCst));
+            let mut events = vec![];
+            while let Some(event) = next_websocket_event(&mut ws).await {
+                events.push(event.event);
+            }
+            assert_eq!(
+                events,
+                [bui_backend_types::GOING_AWAY_EVENT, CALLBACK_REPLY_EVENT]
+            );
+        });
+    }
+
+    #[test]
+    fn callback_response_encode_error() {
+        // JSON maps cannot have tuples as keys, so this response cannot be
+        // encoded.
+        let e = call(&EchoHandler, 1, ReplyFormat::Http(Encoding::Json)).unwrap_err();
+        assert_eq!(
+            callback_error_response(e).0,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn callback_error_details() {
+        let e = CallbackError::unprocessable_entity("invalid")
+            .with_details(serde_json::json!({"field": "name"}))
+            .unwrap();
+        let (status, body) = callback_error_response(Box::new(e));
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            serde_json::to_value(&body).unwrap(),
+            serde_json::json!({"errors": ["invalid"], "details": {"field": "name"}})
+        );
+
+        let details: std::collections::BTreeMap<_, _> = Some(((1, 2), 3)).into_iter().collect();
+        assert!(CallbackError::bad_request("x")
+            .with_details(details)
+            .is_err());
+    }
+
+    pub(crate) fn request(uri: &str) -> http::Request<RequestBody> {
+        use http_body_util::BodyExt;
+        let body = http_body_util::Empty::<bytes::Bytes>::new()
+            .map_err(|never| match never {})
+            .boxed_unsync();
+        http::Request::get(uri).body(body).unwrap()
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn stripped(base_path: &str, uri: &str) -> Result<String, http::Response<MyBody>> {
+        strip_base_path(base_path, request(uri)).map(|req| req.uri().to_string())
+    }
+
+    #[test]
+    fn strip_base_path_below_base() {
+        assert_eq!(stripped("/cam1", "/cam1/").unwrap(), "/");
+        assert_eq!(
+            stripped("/cam1", "/cam1/events?token=x").unwrap(),
+            "/events?token=x"
+        );
+        assert_eq!(stripped("/a/b", "/a/b/c/d.js").unwrap(), "/c/d.js");
+        // Without a base path, requests are unchanged.
+        assert_eq!(stripped("", "/cam1/x?y").unwrap(), "/cam1/x?y");
+    }
+
+    #[test]
+    fn strip_base_path_redirects_to_trailing_slash() {
+        let resp = stripped("/cam1", "/cam1?token=x").unwrap_err();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers()[hyper::header::LOCATION], "/cam1/?token=x");
+    }
+
+    #[test]
+    fn strip_base_path_rejects_other_paths() {
+        for uri in ["/", "/cam", "/cam10/", "/other/cam1/"] {
+            let resp = stripped("/cam1", uri).unwrap_err();
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    fn origin_allowed_with(headers: &[(&str, &str)], allowed_origins: &[&str]) -> bool {
+        let mut req = request("/events");
+        for (name, value) in headers {
+            let name = hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap();
+            req.headers_mut().append(name, value.parse().unwrap());
+        }
+        let allowed_origins: Vec<String> = allowed_origins.iter().map(|o| o.to_string()).collect();
+        origin_allowed(&req, &allowed_origins)
+    }
+
+    #[test]
+    fn websocket_origin() {
+        let local = [("host", "localhost:3410")];
+        assert!(origin_allowed_with(&local, &[]));
+        assert!(origin_allowed_with(
+            &[("origin", "http://LOCALHOST:3410"), local[0]],
+            &[]
+        ));
+        assert!(!origin_allowed_with(
+            &[("origin", "http://evil.example"), local[0]],
+            &[]
+        ));
+        assert!(!origin_allowed_with(
+            &[("origin", "http://localhost:3410")],
+            &[]
+        ));
+
+        // Behind a reverse proxy, the forwarded host is checked.
+        let proxied = [
+            ("origin", "https://example.com"),
+            ("host", "localhost:3410"),
+            ("x-forwarded-host", "example.com, proxy.internal"),
+        ];
+        assert!(origin_allowed_with(&proxied, &[]));
+        assert!(!origin_allowed_with(
+            &[
+                ("origin", "http://localhost:3410"),
+                ("host", "localhost:3410"),
+                ("x-forwarded-host", "example.com"),
+            ],
+            &[]
+        ));
+
+        // Further origins can be allowed.
+        let rewritten = [("origin", "https://example.com"), local[0]];
+        assert!(!origin_allowed_with(&rewritten, &[]));
+        assert!(origin_allowed_with(&rewritten, &["https://example.com/"]));
+        assert!(!origin_allowed_with(&rewritten, &["http://example.com"]));
+    }
+}
@@ -0,0 +1,447 @@
+//! Sessions of the browsers using the server
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use bui_backend_types::{ConnectionKey, Identity, Role, SessionKey};
+
+use crate::{
+    lowlevel::{EventStreamCloser, StreamRejected},
+    Address,
+};
+
+/// Information about a session (i.e. a browser).
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    /// The key identifying the session.
+    pub session_key: SessionKey,
+    /// When the session was first seen by this server.
+    ///
+    /// Sessions started before the server was restarted are seen again with
+    /// their first request afterwards.
+    pub created: SystemTime,
+    /// When the last request of the session was received.
+    pub last_seen: SystemTime,
+    /// The identity with which the session was authenticated, if any.
+    pub identity: Option<Identity>,
+    /// The role of the session.
+    pub role: Role,
+    /// The address from which the last request was received, if known.
+    ///
+    /// When the [crate::lowlevel::BuiService] is mounted in another server,
+    /// this is known if that server adds an [Address] extension to requests.
+    pub remote_addr: Option<Address>,
+    /// The number of open event streams.
+    pub event_streams: usize,
+}
+
+struct Session {
+    created: SystemTime,
+    last_seen: SystemTime,
+    identity: Option<Identity>,
+    role: Role,
+    remote_addr: Option<Address>,
+    /// When the latest session cookie expires, if sessions have a lifetime.
+    expires: Option<SystemTime>,
+    streams: HashMap<ConnectionKey, EventStreamCloser>,
+}
+
+/// How long sessions without a lifetime are remembered after their last
+/// request.
+pub(crate) const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The number of revoked sessions remembered if sessions have no lifetime.
+pub(crate) const MAX_REVOKED: usize = 10_000;
+
+/// The number of sessions remembered. Beyond this, the least recently seen
+/// sessions without event streams are forgotten.
+pub(crate) const MAX_SESSIONS: usize = 10_000;
+
+/// Keeps track of the sessions and their event streams.
+#[derive(Default)]
+pub(crate) struct SessionRegistry {
+    sessions: HashMap<SessionKey, Session>,
+    /// Sessions which were revoked, with the time they were revoked.
+    revoked: HashMap<SessionKey, SystemTime>,
+    total_streams: usize,
+}
+
+impl SessionRegistry {
+    pub(crate) fn is_revoked(&self, session_key: &SessionKey) -> bool {
+        self.revoked.contains_key(session_key)
+    }
+
+    /// When the session expires, if it is known and sessions have a
+    /// lifetime.
+    pub(crate) fn expires(&self, session_key: &SessionKey) -> Option<SystemTime> {
+        self.sessions
+            .get(session_key)
+            .and_then(|session| session.expires)
+    }
+
+    /// Record a request of the session, whose cookie expires at `expires`.
+    ///
+    /// If the session is new and [MAX_SESSIONS] are known, the least recently
+    /// seen session without event streams is forgotten. It is seen again with
+    /// its next request.
+    pub(crate) fn seen(
+        &mut self,
+        session_key: SessionKey,
+        remote_addr: Option<Address>,
+        identity: Option<&Identity>,
+        role: Role,
+        expires: Option<SystemTime>,
+    ) {
+        let now = SystemTime::now();
+        if !self.sessions.contains_key(&session_key) {
+            self.forget_idle(MAX_SESSIONS - 1);
+        }
+        let session = self.sessions.entry(session_key).or_insert_with(|| Session {
+            created: now,
+            last_seen: now,
+            identity: identity.cloned(),
+            role,
+            remote_addr: None,
+            expires: None,
+            streams: HashMap::new(),
+        });
+        session.last_seen = now;
+        // Requests may still carry an older cookie after it was refreshed.
+        session.expires = session.expires.max(expires);
+        if remote_addr.is_some() {
+            session.remote_addr = remote_addr;
+        }
+    }
+
+    /// Forget sessions not seen for longer than `lifetime`, which have
+    /// therefore expired, or, without a lifetime, for longer than
+    /// [DEFAULT_IDLE_TIMEOUT].
+    ///
+    /// Revoked sessions are remembered until their cookies have expired.
+    /// Without a lifetime, cookies do not expire, so only the [MAX_REVOKED]
+    /// most recently revoked sessions are remembered.
+    pub(crate) fn prune(&mut self, lifetime: Option<Duration>) {
+        let now = SystemTime::now();
+        let older_than = |t: &SystemTime, max_age: Duration| {
+            now.duration_since(*t)
+                .map(|age| age > max_age)
+                .unwrap_or(false)
+        };
+        let idle_timeout = lifetime.unwrap_or(DEFAULT_IDLE_TIMEOUT);
+        self.sessions.retain(|_, session| {
+            !session.streams.is_empty() || !older_than(&session.last_seen, idle_timeout)
+        });
+        match lifetime {
+            Some(lifetime) => self
+                .revoked
+                .retain(|_, revoked| !older_than(revoked, lifetime)),
+            None if self.revoked.len() > MAX_REVOKED => {
+                let mut times: Vec<SystemTime> = self.revoked.values().copied().collect();
+                times.sort_unstable();
+                let oldest_kept = times[times.len() - MAX_REVOKED];
+                self.revoked.retain(|_, revoked| *revoked >= oldest_kept);
+            }
+            None => {}
+        }
+    }
+
+    /// Forget the least recently seen sessions without event streams until at
+    /// most `max` sessions are known.
+    fn forget_idle(&mut self, max: usize) {
+        if self.sessions.len() <= max {
+            return;
+        }
+        let mut idle: Vec<(SystemTime, SessionKey)> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.streams.is_empty())
+            .map(|(session_key, session)| (session.last_seen, *session_key))
+            .collect();
+        idle.sort_unstable_by_key(|(last_seen, _)| *last_seen);
+        let excess = self.sessions.len() - max;
+        for (_, session_key) in idle.iter().take(excess) {
+            self.sessions.remove(session_key);
+        }
+    }
+
+    /// Count a new event stream of the session, unless a limit is reached.
+    pub(crate) fn add_stream(
+        &mut self,
+        session_key: SessionKey,
+        connection_key: ConnectionKey,
+        closer: EventStreamCloser,
+        max_total: Option<usize>,
+        max_per_session: Option<usize>,
+    ) -> Result<(), StreamRejected> {
+        if let Some(max) = max_total {
+            if self.total_streams >= max {
+                return Err(StreamRejected::TooMany);
+            }
+        }
+        let session = match self.sessions.get_mut(&session_key) {
+            Some(session) => session,
+            None => return Err(StreamRejected::SessionEnded),
+        };
+        if let Some(max) = max_per_session {
+            if session.streams.len() >= max {
+                return Err(StreamRejected::TooManyForSession);
+            }
+        }
+        session.streams.insert(connection_key, closer);
+        self.total_streams += 1;
+        Ok(())
+    }
+
+    /// Stop counting an event stream which was closed.
+    pub(crate) fn remove_stream(
+        &mut self,
+        session_key: &SessionKey,
+        connection_key: &ConnectionKey,
+    ) {
+        self.total_streams -= 1;
+        if let Some(session) = self.sessions.get_mut(session_key) {
+            session.streams.remove(connection_key);
+        }
+    }
+
+    /// End the session and close its event streams.
+    ///
+    /// Returns whether the session was known.
+    pub(crate) fn revoke(&mut self, session_key: SessionKey) -> bool {
+        self.revoked.insert(session_key, SystemTime::now());
+        match self.sessions.remove(&session_key) {
+            Some(session) => {
+                for closer in session.streams.values() {
+                    closer.close();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// End all sessions and close their event streams.
+    pub(crate) fn revoke_all(&mut self) {
+        let keys: Vec<SessionKey> = self.sessions.keys().copied().collect();
+        for session_key in keys {
+            self.revoke(session_key);
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .iter()
+            .map(|(session_key, session)| SessionInfo {
+                session_key: *session_key,
+                created: session.created,
+                last_seen: session.last_seen,
+                identity: session.identity.clone(),
+                role: session.role,
+                remote_addr: session.remote_addr.clone(),
+                event_streams: session.streams.len(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_sessions(sessions: usize) -> (SessionRegistry, Vec<SessionKey>) {
+        let mut registry = SessionRegistry::default();
+        let keys: Vec<SessionKey> = (0..sessions).map(|_| SessionKey::new()).collect();
+        for key in keys.iter() {
+            registry.seen(*key, None, None, Role::Controller, None);
+        }
+        (registry, keys)
+    }
+
+    fn add(
+        registry: &mut SessionRegistry,
+        session_key: SessionKey,
+        n: u32,
+        max_total: Option<usize>,
+        max_per_session: Option<usize>,
+    ) -> Result<(), StreamRejected> {
+        registry.add_stream(
+            session_key,
+            ConnectionKey(n),
+            EventStreamCloser::default(),
+            max_total,
+            max_per_session,
+        )
+    }
+
+    #[test]
+    fn stream_limit_per_session() {
+        let (mut registry, keys) = with_sessions(2);
+        let max = Some(2);
+        add(&mut registry, keys[0], 0, None, max).unwrap();
+        add(&mut registry, keys[0], 1, None, max).unwrap();
+        assert!(matches!(
+            add(&mut registry, keys[0], 2, None, max),
+            Err(StreamRejected::TooManyForSession)
+        ));
+        // Other sessions have their own limit.
+        add(&mut registry, keys[1], 3, None, max).unwrap();
+
+        // Closing a stream makes room for another.
+        registry.remove_stream(&keys[0], &ConnectionKey(0));
+        add(&mut registry, keys[0], 4, None, max).unwrap();
+    }
+
+    #[test]
+    fn stream_limit_total() {
+        let (mut registry, keys) = with_sessions(2);
+        let max = Some(2);
+        add(&mut registry, keys[0], 0, max, None).unwrap();
+        add(&mut registry, keys[1], 1, max, None).unwrap();
+        assert!(matches!(
+            add(&mut registry, keys[1], 2, max, None),
+            Err(StreamRejected::TooMany)
+        ));
+
+        registry.remove_stream(&keys[0], &ConnectionKey(0));
+        add(&mut registry, keys[1], 3, max, None).unwrap();
+    }
+
+    #[test]
+    fn streams_of_ended_sessions() {
+        let (mut registry, keys) = with_sessions(2);
+        add(&mut registry, keys[0], 0, Some(2), None).unwrap();
+        add(&mut registry, keys[0], 1, Some(2), None).unwrap();
+        assert!(registry.revoke(keys[0]));
+        assert!(registry.is_revoked(&keys[0]));
+        assert!(matches!(
+            add(&mut registry, keys[0], 2, None, None),
+            Err(StreamRejected::SessionEnded)
+        ));
+        assert!(matches!(
+            add(&mut registry, SessionKey::new(), 3, None, None),
+            Err(StreamRejected::SessionEnded)
+        ));
+
+        // The streams of the revoked session count until they are closed.
+        assert!(matches!(
+            add(&mut registry, keys[1], 4, Some(2), None),
+            Err(StreamRejected::TooMany)
+        ));
+        registry.remove_stream(&keys[0], &ConnectionKey(0));
+        registry.remove_stream(&keys[0], &ConnectionKey(1));
+        add(&mut registry, keys[1], 5, Some(2), None).unwrap();
+        let list = registry.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].event_streams, 1);
+    }
+
+    fn age(registry: &mut SessionRegistry, session_key: &SessionKey, by: Duration) {
+        let session = registry.sessions.get_mut(session_key).unwrap();
+        session.last_seen -= by;
+    }
+
+    #[test]
+    fn prune_idle_sessions() {
+        let minute = Duration::from_secs(60);
+        let (mut registry, keys) = with_sessions(3);
+        age(&mut registry, &keys[0], 2 * minute);
+        age(&mut registry, &keys[1], 2 * minute);
+        add(&mut registry, keys[1], 0, None, None).unwrap();
+        registry.prune(Some(minute));
+        // Sessions with open event streams are kept.
+        let mut kept: Vec<SessionKey> = registry.list().iter().map(|s| s.session_key).collect();
+        kept.sort_by_key(|k| k.0);
+        let mut expected = vec![keys[1], keys[2]];
+        expected.sort_by_key(|k| k.0);
+        assert_eq!(kept, expected);
+
+        // Without a lifetime, sessions are forgotten after the default idle
+        // timeout.
+        let (mut registry, keys) = with_sessions(2);
+        age(&mut registry, &keys[0], DEFAULT_IDLE_TIMEOUT + minute);
+        age(&mut registry, &keys[1], DEFAULT_IDLE_TIMEOUT - minute);
+        registry.prune(None);
+        let list = registry.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].session_key, keys[1]);
+    }
+
+    #[test]
+    fn session_expiry() {
+        let (mut registry, keys) = with_sessions(1);
+        assert_eq!(registry.expires(&keys[0]), None);
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(60);
+        registry.seen(keys[0], None, None, Role::Controller, Some(later));
+        assert_eq!(registry.expires(&keys[0]), Some(later));
+        // A request with the cookie from before it was refreshed does not
+        // shorten the session.
+        registry.seen(keys[0], None, None, Role::Controller, Some(now));
+        assert_eq!(registry.expires(&keys[0]), Some(later));
+        assert_eq!(registry.expires(&SessionKey::new()), None);
+    }
+
+    #[test]
+    fn forget_idle_sessions() {
+        let minute = Duration::from_secs(60);
+        let (mut registry, keys) = with_sessions(4);
+        age(&mut registry, &keys[0], 3 * minute);
+        age(&mut registry, &keys[1], 2 * minute);
+        age(&mut registry, &keys[2], minute);
+        add(&mut registry, keys[0], 0, None, None).unwrap();
+        registry.forget_idle(2);
+        // The least recently seen sessions without event streams are
+        // forgotten.
+        let mut kept: Vec<SessionKey> = registry.list().iter().map(|s| s.session_key).collect();
+        kept.sort_by_key(|k| k.0);
+        let mut expected = vec![keys[0], keys[3]];
+        expected.sort_by_key(|k| k.0);
+        assert_eq!(kept, expected);
+
+        // Sessions with event streams are kept beyond the limit.
+        registry.forget_idle(0);
+        let list = registry.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].session_key, keys[0]);
+    }
+
+    #[test]
+    fn session_limit() {
+        let (mut registry, keys) = with_sessions(MAX_SESSIONS);
+        registry.seen(keys[0], None, None, Role::Controller, None);
+        assert_eq!(registry.list().len(), MAX_SESSIONS);
+        let key = SessionKey::new();
+        registry.seen(key, None, None, Role::Controller, None);
+        assert_eq!(registry.list().len(), MAX_SESSIONS);
+        assert!(registry.sessions.contains_key(&key));
+        assert!(registry.sessions.contains_key(&keys[0]));
+    }
+
+    #[test]
+    fn prune_revoked_sessions() {
+        let minute = Duration::from_secs(60);
+        let (mut registry, keys) = with_sessions(2);
+        registry.revoke(keys[0]);
+        registry.revoke(keys[1]);
+        *registry.revoked.get_mut(&keys[0]).unwrap() -= 2 * minute;
+        registry.prune(Some(minute));
+        assert!(!registry.is_revoked(&keys[0]));
+        assert!(registry.is_revoked(&keys[1]));
+
+        // Without a lifetime, the most recent revocations are kept.
+        let mut registry = SessionRegistry::default();
+        let now = SystemTime::now();
+        let keys: Vec<SessionKey> = (0..MAX_REVOKED + 5).map(|_| SessionKey::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let revoked = now - Duration::from_secs((keys.len() - i) as u64);
+            registry.revoked.insert(*key, revoked);
+        }
+        registry.prune(None);
+        assert_eq!(registry.revoked.len(), MAX_REVOKED);
+        assert!(!registry.is_revoked(&keys[4]));
+        assert!(registry.is_revoked(&keys[5]));
+        assert!(registry.is_revoked(keys.last().unwrap()));
+    }
+}
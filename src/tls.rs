@@ -0,0 +1,97 @@
+//! Serving over HTTPS
+//!
+//! Set `Config::tls` to serve using TLS. Creating a [TlsConfig] requires the
+//! `tls` cargo feature. HTTP/2 is negotiated with clients supporting it.
+
+#[cfg(feature = "tls")]
+use crate::Error;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+/// Certificate and private key with which to serve over HTTPS.
+///
+/// When set, session cookies are marked `Secure`.
+#[derive(Clone)]
+pub struct TlsConfig {
+    #[cfg(feature = "tls")]
+    pub(crate) server_config: Arc<tokio_rustls::rustls::ServerConfig>,
+    // Prevent construction without the `tls` feature.
+    _priv: (),
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "tls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tls")))]
+impl TlsConfig {
+    /// Load a certificate chain and private key in PEM format.
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, Error> {
+        let certs = rustls_pemfile::certs(&mut &cert_pem[..])
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::Tls(format!("reading certificates: {}", e)))?;
+        if certs.is_empty() {
+            return Err(Error::Tls("no certificate found".into()));
+        }
+        let key = rustls_pemfile::private_key(&mut &key_pem[..])
+            .map_err(|e| Error::Tls(format!("reading private key: {}", e)))?
+            .ok_or_else(|| Error::Tls("no private key found".into()))?;
+
+        let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+        let mut server_config = tokio_rustls::rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(e.to_string()))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| Error::Tls(e.to_string()))?;
+        // Offer HTTP/2 so browsers multiplex all event streams over one
+        // connection rather than running into their per-host limit of
+        // HTTP/1.1 connections.
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Self {
+            server_config: Arc::new(server_config),
+            _priv: (),
+        })
+    }
+
+    /// Load a certificate chain and private key from PEM files.
+    pub fn from_pem_files<P1, P2>(cert_path: P1, key_path: P2) -> Result<Self, Error>
+    where
+        P1: AsRef<std::path::Path>,
+        P2: AsRef<std::path::Path>,
+    {
+        let cert_pem = std::fs::read(cert_path)?;
+        let key_pem = std::fs::read(key_path)?;
+        Self::from_pem(&cert_pem, &key_pem)
+    }
+
+    /// Generate a self-signed certificate for the given host names.
+    ///
+    /// Browsers warn about self-signed certificates, so this is meant for
+    /// testing and use on trusted networks.
+    pub fn self_signed(subject_alt_names: &[&str]) -> Result<Self, Error> {
+        let (cert_pem, key_pem) = generate_self_signed(subject_alt_names)?;
+        Self::from_pem(cert_pem.as_bytes(), key_pem.as_bytes())
+    }
+
+    pub(crate) fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        tokio_rustls::TlsAcceptor::from(self.server_config.clone())
+    }
+}
+
+/// Generate a self-signed certificate for the given host names.
+///
+/// Returns the certificate and private key in PEM format, for example to save
+/// them for later use with [TlsConfig::from_pem_files].
+#[cfg(feature = "tls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tls")))]
+pub fn generate_self_signed(subject_alt_names: &[&str]) -> Result<(String, String), Error> {
+    let names: Vec<String> = subject_alt_names.iter().map(|s| s.to_string()).collect();
+    let certified = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| Error::Tls(format!("generating certificate: {}", e)))?;
+    Ok((certified.cert.pem(), certified.signing_key.serialize_pem()))
+}
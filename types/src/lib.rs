@@ -17,19 +17,264 @@ impl SessionKey {
     }
 }
 
+#[cfg(feature = "uuid-v4")]
+#[cfg_attr(docsrs, doc(cfg(feature = "uuid-v4")))]
+impl Default for SessionKey {
+    /// Create a new random SessionKey
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Identifier for each connected event stream listener (one per client tab).
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct ConnectionKey(pub u32);
 
+/// The identity of an authenticated user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    /// The user name.
+    pub username: String,
+}
+
+impl Identity {
+    /// Create the identity of the user `username`.
+    pub fn new<S: Into<String>>(username: S) -> Self {
+        Self {
+            username: username.into(),
+        }
+    }
+}
+
+/// What a session is allowed to do.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Role {
+    /// May open event streams but not send callbacks.
+    Viewer,
+    /// May open event streams and send callbacks.
+    ///
+    /// This is the role of sessions started without authentication or with
+    /// a single pre-shared token.
+    #[default]
+    Controller,
+}
+
 /// Callback data from a connected client.
+///
+/// Fields may be added in future versions, so values are created with
+/// [CallbackDataAndSession::new].
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct CallbackDataAndSession<T> {
     /// The callback data sent from the client.
     pub payload: T,
     /// The session key associated with the client.
     pub session_key: SessionKey,
+    /// The identity with which the session was authenticated, if any.
+    ///
+    /// This is `None` for sessions started without authentication or with a
+    /// pre-shared token.
+    pub identity: Option<Identity>,
+    /// The role of the session.
+    pub role: Role,
+}
+
+impl<T> CallbackDataAndSession<T> {
+    /// Create callback data of the session `session_key` with no identity and
+    /// the default role.
+    pub fn new(payload: T, session_key: SessionKey) -> Self {
+        Self {
+            payload,
+            session_key,
+            identity: None,
+            role: Role::default(),
+        }
+    }
+}
+
+/// The name of the event stream event carrying a patch to the state.
+///
+/// `event_name` is the name of the event carrying the full state, or `None`
+/// if the default event type (`message`) is used.
+pub fn patch_event_name(event_name: Option<&str>) -> String {
+    format!("{}-patch", event_name.unwrap_or("message"))
+}
+
+/// The name of the event sent over a WebSocket when a callback fails.
+///
+/// The data of this event is a JSON object with an `errors` field containing
+/// a list of error strings.
+pub const CALLBACK_ERROR_EVENT: &str = "callback-error";
+
+/// The name of the event sent over a WebSocket with the response to a
+/// callback.
+///
+/// The data of this event is the response returned by the callback handler.
+pub const CALLBACK_REPLY_EVENT: &str = "callback-reply";
+
+/// The name of the event sent before the server closes an event stream
+/// because it is shutting down.
+///
+/// Clients receiving this event should not reconnect immediately.
+pub const GOING_AWAY_EVENT: &str = "going-away";
+
+/// A message sent from the server to the client over a WebSocket.
+///
+/// Each message carries the same content as a server sent event would.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebSocketEvent {
+    /// The event id, if any.
+    ///
+    /// To resume after reconnecting, pass the id of the last received event
+    /// in the `last_event_id` query parameter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The event name (`message` if none was specified).
+    pub event: String,
+    /// The event data.
+    pub data: String,
+}
+
+/// A message sent from the server to the client over a WebSocket using a
+/// binary encoding.
+///
+/// This is the binary counterpart of [WebSocketEvent], with the event data
+/// decoded rather than as a JSON string. Decode it with [Encoding::decode]
+/// using the encoding negotiated when opening the WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BinaryWebSocketEvent<D> {
+    /// The event id, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The event name (`message` if none was specified).
+    pub event: String,
+    /// The event data.
+    pub data: D,
+}
+
+/// An encoding of callback payloads and event data.
+///
+/// Callbacks are decoded according to their `Content-Type` header. Clients
+/// opening a WebSocket select the encoding of both directions by requesting
+/// the subprotocol given by [Encoding::websocket_protocol]. Messages in a
+/// binary encoding are sent in binary WebSocket frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Encoding {
+    /// JSON
+    Json,
+    /// MessagePack
+    #[cfg(feature = "msgpack")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "msgpack")))]
+    MessagePack,
+    /// CBOR
+    #[cfg(feature = "cbor")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "cbor")))]
+    Cbor,
+}
+
+impl Encoding {
+    /// The MIME type of this encoding.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    /// Find the encoding for a `Content-Type` header value.
+    ///
+    /// Parameters such as `charset` are ignored.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Encoding::Json),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Encoding::MessagePack)
+            }
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The WebSocket subprotocol with which a client selects this encoding.
+    pub fn websocket_protocol(&self) -> &'static str {
+        match self {
+            Encoding::Json => "bui.json",
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => "bui.msgpack",
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => "bui.cbor",
+        }
+    }
+
+    /// Find the encoding for a WebSocket subprotocol.
+    pub fn from_websocket_protocol(protocol: &str) -> Option<Self> {
+        match protocol.trim() {
+            "bui.json" => Some(Encoding::Json),
+            #[cfg(feature = "msgpack")]
+            "bui.msgpack" => Some(Encoding::MessagePack),
+            #[cfg(feature = "cbor")]
+            "bui.cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a binary encoding.
+    pub fn is_binary(&self) -> bool {
+        !matches!(self, Encoding::Json)
+    }
+
+    /// Encode a value.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, EncodingError> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(EncodingError::new),
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(EncodingError::new),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf).map_err(EncodingError::new)?;
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Decode a value.
+    pub fn decode<T: serde::de::DeserializeOwned>(&self, buf: &[u8]) -> Result<T, EncodingError> {
+        match self {
+            Encoding::Json => serde_json::from_slice(buf).map_err(EncodingError::new),
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => rmp_serde::from_slice(buf).map_err(EncodingError::new),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => ciborium::from_reader(buf).map_err(EncodingError::new),
+        }
+    }
 }
 
+/// An error encoding or decoding a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodingError(String);
+
+impl EncodingError {
+    fn new<E: std::fmt::Display>(e: E) -> Self {
+        EncodingError(e.to_string())
+    }
+}
+
+impl std::fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for EncodingError {}
+
 /// A token which can be required to gain access to HTTP API
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum AccessToken {